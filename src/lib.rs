@@ -0,0 +1,38 @@
+#[macro_use]
+mod rs_log;
+
+mod rs_animation;
+mod rs_ast;
+mod rs_error;
+mod rs_group;
+mod rs_interpreter;
+mod rs_label;
+mod rs_operators;
+mod rs_parser;
+mod rs_path;
+mod rs_procedure;
+mod rs_random;
+mod rs_reverse;
+mod rs_stack;
+mod rs_turtle;
+mod rs_variables;
+
+pub use rs_ast::{Command, Program};
+pub use rs_error::{line_and_column, ErrorReport, RSLogoError};
+pub use rs_interpreter::{Interpreter, DEFAULT_MAX_ITERATIONS};
+pub use rs_log::{is_verbose, set_verbose};
+pub use rs_parser::{parse_program, parse_program_with_includes};
+pub use rs_procedure::{describe_procedures, lint, DEFAULT_MAX_DEPTH};
+pub use rs_turtle::{
+    default_palette, parse_color, parse_edge_mode, parse_origin, parse_palette, parse_y_axis,
+    EdgeMode, Origin, Turtle, TurtleSnapshot, YAxis, DEFAULT_JPEG_QUALITY,
+};
+
+// Parse and run a whole program, handing back the interpreter so callers can
+// inspect the final turtle state or save the image themselves
+pub fn run_source(source: &str, width: u32, height: u32) -> Result<Interpreter, RSLogoError> {
+    let program = parse_program(source)?;
+    let mut interpreter = Interpreter::new(width, height);
+    interpreter.execute(&program)?;
+    Ok(interpreter)
+}
@@ -1,71 +1,342 @@
-mod rs_ast;
-mod rs_error;
-mod rs_interpreter;
-mod rs_operators;
-mod rs_parser;
-mod rs_procedure;
-mod rs_stack;
-mod rs_turtle;
-mod rs_variables;
-
 use clap::Parser;
-use rs_error::RSLogoError;
+use rustle::{
+    default_palette, describe_procedures, line_and_column, lint, parse_color, parse_edge_mode,
+    parse_origin, parse_palette, parse_program_with_includes, parse_y_axis, set_verbose, trace,
+    Interpreter, RSLogoError, DEFAULT_JPEG_QUALITY, DEFAULT_MAX_DEPTH, DEFAULT_MAX_ITERATIONS,
+};
 use std::fs;
+use std::io;
 use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const INTERPRETER_STACK_SIZE: usize = 256 * 1024 * 1024;
 
 #[derive(Parser)]
 struct Args {
-    /// Input file
+    /// Input file, or - to read the program from stdin
     #[arg(value_name = "INPUT")]
     file_path: PathBuf,
 
-    /// Output file
-    #[arg(value_name = "OUTPUT")]
-    image_path: PathBuf,
+    /// Output file (.svg, .png, .gif or .jpg), or - to print the SVG to stdout
+    #[arg(value_name = "OUTPUT", required_unless_present_any = ["check", "list_procedures"])]
+    image_path: Option<PathBuf>,
 
     /// Image height
-    #[arg(value_name = "HEIGHT")]
-    height: u32,
+    #[arg(value_name = "HEIGHT", required_unless_present_any = ["check", "list_procedures"])]
+    height: Option<u32>,
 
     /// Image width
-    #[arg(value_name = "WIDTH")]
-    width: u32,
+    #[arg(value_name = "WIDTH", required_unless_present_any = ["check", "list_procedures"])]
+    width: Option<u32>,
+
+    /// Only check that the program parses and that its procedure calls and
+    /// variables resolve; nothing is run or drawn
+    #[arg(long)]
+    check: bool,
+
+    /// List the procedures the program defines, with their parameters and
+    /// the number of commands in each body, without running anything
+    #[arg(long, conflicts_with = "check")]
+    list_procedures: bool,
+
+    /// Background color: a palette index, color name, #RRGGBB or #RGB
+    #[arg(long, value_name = "COLOR")]
+    background: Option<String>,
+
+    /// Pen color before any SETPENCOLOR: a palette index, color name,
+    /// #RRGGBB or #RGB [default: white]
+    #[arg(long, value_name = "COLOR")]
+    pen_color: Option<String>,
+
+    /// File of up to 16 #RRGGBB colors, one per line, that SETPENCOLOR
+    /// indexes into instead of the default palette
+    #[arg(long, value_name = "FILE")]
+    palette: Option<PathBuf>,
+
+    /// What movements do at the canvas border: clamp, wrap, or ignore
+    #[arg(long, value_name = "MODE", default_value = "ignore")]
+    edge_mode: String,
+
+    /// Where XCOR/YCOR 0 0 is on the canvas: topleft, center, or bottomleft.
+    /// The turtle always starts in the middle of the canvas.
+    #[arg(long, value_name = "ORIGIN", default_value = "topleft")]
+    origin: String,
+
+    /// Which way YCOR grows on screen: down or up
+    #[arg(long, value_name = "DIRECTION", default_value = "down")]
+    y_axis: String,
+
+    /// Start with the pen down, so the first movement draws without a PENDOWN
+    #[arg(long)]
+    pen_down: bool,
+
+    /// Maximum depth of nested procedure calls
+    #[arg(long, value_name = "DEPTH", default_value_t = DEFAULT_MAX_DEPTH)]
+    max_depth: usize,
+
+    /// Maximum number of times a single loop may run its body
+    #[arg(long, value_name = "COUNT", default_value_t = DEFAULT_MAX_ITERATIONS)]
+    max_iterations: usize,
+
+    /// Stop with an error if the program runs for longer than this many
+    /// seconds (fractions allowed)
+    #[arg(long, value_name = "SECONDS", value_parser = parse_seconds)]
+    timeout: Option<Duration>,
+
+    /// Print how long parsing, running and saving took to stderr
+    #[arg(long)]
+    profile: bool,
+
+    /// Print diagnostic traces while parsing and running
+    #[arg(short, long)]
+    verbose: bool,
+
+    /// How errors are reported: human-readable on stdout, or one JSON
+    /// object per error on stderr
+    #[arg(
+        long,
+        value_name = "FORMAT",
+        default_value = "human",
+        value_parser = ["human", "json"]
+    )]
+    error_format: String,
+
+    /// Print each command to stderr before it runs, with its argument values
+    /// and the turtle's position, heading, pen and color. Independent of
+    /// --verbose.
+    #[arg(long)]
+    trace: bool,
+
+    /// Warn on stderr whenever a command leaves the turtle off the canvas
+    #[arg(long)]
+    warn_offscreen: bool,
+
+    /// Suppress diagnostic traces (the default)
+    #[arg(short, long, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Seed for RANDOM; a time-based seed is used when omitted
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// Record a frame after each movement and write an animated .gif
+    #[arg(long)]
+    animate: bool,
+
+    /// Also write every line drawn to a JSON file, as a list of segments
+    /// with their endpoints in canvas pixels and their color
+    #[arg(long, value_name = "FILE")]
+    export_path: Option<PathBuf>,
+
+    /// Maximum number of frames recorded with --animate
+    #[arg(long, value_name = "FRAMES", default_value_t = 500)]
+    max_frames: usize,
+
+    /// Multiply the output size; HEIGHT and WIDTH stay the logical canvas
+    /// that turtle coordinates refer to
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    scale: u32,
+
+    /// JPEG quality from 1 to 100
+    #[arg(
+        long,
+        value_name = "QUALITY",
+        default_value_t = DEFAULT_JPEG_QUALITY,
+        value_parser = clap::value_parser!(u8).range(1..=100)
+    )]
+    quality: u8,
 }
 
 fn main() {
     let args = Args::parse();
+    set_verbose(args.verbose && !args.quiet);
+    let json_errors = args.error_format == "json";
+
+    // Deep (but limited) Logo recursion needs more native stack than the
+    // main thread gets by default
+    let result = thread::Builder::new()
+        .stack_size(INTERPRETER_STACK_SIZE)
+        .spawn(move || run(args))
+        .expect("failed to spawn interpreter thread")
+        .join()
+        .unwrap_or_else(|panic| std::panic::resume_unwind(panic));
 
-    if let Err(err) = run(args) {
-        report_error(&err);
+    if let Err(err) = result {
+        report(&err, json_errors);
         std::process::exit(1);
     } else {
-        println!("Program executed successfully.");
+        trace!("Program executed successfully.");
     }
 }
 
 fn run(args: Args) -> Result<(), RSLogoError> {
-    println!("Reading input file...");
-    let input = fs::read_to_string(&args.file_path)?;
-    println!("Input file content: '{}'", input);
+    // Reject a bad --palette, --background, --pen-color, --edge-mode,
+    // --origin or --y-axis before doing any work
+    let palette = match &args.palette {
+        Some(path) => parse_palette(&fs::read_to_string(path)?)?,
+        None => default_palette(),
+    };
+    let background = args
+        .background
+        .as_deref()
+        .map(|spec| parse_color(spec, &palette))
+        .transpose()?;
+    let pen_color = args
+        .pen_color
+        .as_deref()
+        .map(|spec| parse_color(spec, &palette))
+        .transpose()?;
+    let edge_mode = parse_edge_mode(&args.edge_mode)?;
+    let origin = parse_origin(&args.origin)?;
+    let y_axis = parse_y_axis(&args.y_axis)?;
+
+    trace!("Reading input file...");
+    let from_stdin = args.file_path.as_os_str() == "-";
+    let input = if from_stdin {
+        io::read_to_string(io::stdin())?
+    } else {
+        fs::read_to_string(&args.file_path)?
+    };
+    trace!("Input file content: '{}'", input);
+
+    trace!("Parsing program...");
+    let parse_started = Instant::now();
+    let file_path = (!from_stdin).then_some(args.file_path.as_path());
+    let program = parse_program_with_includes(&input, file_path)?;
+    trace!("Parsed program: {:?}", program);
+    trace!("Number of commands: {}", program.commands.len());
+    let parse_time = parse_started.elapsed();
 
-    println!("Parsing program...");
-    let program = rs_parser::parse_program(&input)?;
-    println!("Parsed program: {:?}", program);
-    println!("Number of commands: {}", program.commands.len());
+    if args.check {
+        let problems = lint(&program.commands);
+        if problems.is_empty() {
+            println!("No problems found.");
+            return Ok(());
+        }
+        let count = problems.len();
+        let json_errors = args.error_format == "json";
+        for problem in problems {
+            report(&problem.with_source(&input), json_errors);
+        }
+        return Err(RSLogoError::CheckFailed(count));
+    }
+    if args.list_procedures {
+        let procedures = describe_procedures(&program.commands);
+        if procedures.is_empty() {
+            println!("No procedures defined.");
+        }
+        for procedure in procedures {
+            println!("{}", procedure);
+        }
+        return Ok(());
+    }
+    // clap requires these whenever --check and --list-procedures are absent
+    let (image_path, height, width) = match (args.image_path, args.height, args.width) {
+        (Some(image_path), Some(height), Some(width)) => (image_path, height, width),
+        _ => unreachable!(
+            "OUTPUT, HEIGHT and WIDTH are required without --check or --list-procedures"
+        ),
+    };
 
-    println!("Creating interpreter...");
-    let mut interpreter = rs_interpreter::Interpreter::new(args.width, args.height);
+    trace!("Creating interpreter...");
+    let mut interpreter = Interpreter::new(width, height);
+    let seed = args.seed.unwrap_or_else(|| {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0)
+    });
+    trace!("Using random seed {}", seed);
+    interpreter.set_seed(seed);
+    interpreter.set_max_depth(args.max_depth);
+    interpreter.set_max_iterations(args.max_iterations);
+    if let Some(timeout) = args.timeout {
+        interpreter.set_timeout(timeout);
+    }
+    interpreter.set_scale(args.scale)?;
+    interpreter.set_edge_mode(edge_mode);
+    interpreter.set_coordinate_system(origin, y_axis);
+    interpreter.set_palette(palette);
+    interpreter.set_pen_down(args.pen_down);
+    if let Some(color) = pen_color {
+        interpreter.set_pen_color(color);
+    }
+    interpreter.set_jpeg_quality(args.quality);
+    interpreter.set_trace_steps(args.trace);
+    interpreter.set_warn_offscreen(args.warn_offscreen);
+    if args.animate {
+        interpreter.enable_animation(args.max_frames);
+    }
+    if args.export_path.is_some() {
+        interpreter.enable_path_recording();
+    }
+    if let Some(color) = background {
+        interpreter.set_background(color)?;
+    }
 
-    println!("Executing program...");
+    trace!("Executing program...");
+    let execute_started = Instant::now();
     interpreter.execute(&program)?;
+    let execute_time = execute_started.elapsed();
+
+    let save_started = Instant::now();
+    if image_path.as_os_str() == "-" {
+        // PNG is binary, so stdout output is always SVG text
+        print!("{}", interpreter.svg_string()?);
+    } else {
+        trace!("Saving image...");
+        interpreter.save_image(&image_path)?;
+    }
+    if let Some(path) = &args.export_path {
+        trace!("Exporting path...");
+        interpreter.save_path(path)?;
+    }
+    let save_time = save_started.elapsed();
 
-    println!("Saving image...");
-    interpreter.save_image(&args.image_path)?;
+    if args.profile {
+        print_profile(&[
+            ("parse", parse_time),
+            ("execute", execute_time),
+            ("save", save_time),
+        ]);
+    }
 
-    println!("Program execution completed.");
+    trace!("Program execution completed.");
     Ok(())
 }
 
+// Milliseconds for each phase and their total, on stderr so they never mix
+// with an SVG written to stdout
+fn print_profile(phases: &[(&str, Duration)]) {
+    eprintln!("Profile:");
+    for (phase, time) in phases {
+        eprintln!("  {:<8} {:>10.3} ms", phase, time.as_secs_f64() * 1000.0);
+    }
+    let total: Duration = phases.iter().map(|(_, time)| *time).sum();
+    eprintln!("  {:<8} {:>10.3} ms", "total", total.as_secs_f64() * 1000.0);
+}
+
+fn parse_seconds(text: &str) -> Result<Duration, String> {
+    match text.parse::<f64>() {
+        Ok(seconds) if seconds > 0.0 => {
+            Duration::try_from_secs_f64(seconds).map_err(|err| err.to_string())
+        }
+        _ => Err(format!(
+            "expected a positive number of seconds, got '{}'",
+            text
+        )),
+    }
+}
+
+fn report(err: &RSLogoError, json: bool) {
+    if json {
+        eprintln!("{}", err.report().to_json());
+    } else {
+        report_error(err);
+    }
+}
+
 fn report_error(err: &RSLogoError) {
     match err {
         RSLogoError::ParseError {
@@ -75,48 +346,16 @@ fn report_error(err: &RSLogoError) {
         } => {
             // Print the error message in red with bold
             println!("\x1b[1;31mError: {}\x1b[0m", message);
-
-            // Print the relevant code snippet with line numbers
-            if !input.is_empty() {
-                println!("\nRelevant code:");
-                let lines: Vec<&str> = input.lines().collect();
-                let start_line = input[..span.0].matches('\n').count();
-
-                // Print a few lines before and after the error for context
-                let context_lines = 2;
-                let start_idx = start_line.saturating_sub(context_lines);
-                let end_idx = (start_line + context_lines + 1).min(lines.len());
-
-                for (idx, line) in lines[start_idx..end_idx].iter().enumerate() {
-                    let line_num = start_idx + idx + 1;
-                    if line_num == start_line + 1 {
-                        // Error line in red
-                        println!("\x1b[31m{:4} | {}\x1b[0m", line_num, line);
-
-                        // Print error pointer
-                        let pointer_offset = if span.0 > line.len() {
-                            line.len()
-                        } else {
-                            span.0 - input[..span.0].rfind('\n').map_or(0, |n| n + 1)
-                        };
-                        println!(
-                            "     | {}\x1b[31m{}\x1b[0m",
-                            " ".repeat(pointer_offset),
-                            "^".repeat(span.1)
-                        );
-
-                        // Print suggestion
-                        println!("     | ");
-                        println!("\x1b[33mHint: 'END' commands must be paired with a 'TO' procedure definition:\x1b[0m");
-                        println!("     | TO procedure_name");
-                        println!("     |    commands...");
-                        println!("     | END");
-                    } else {
-                        // Context lines in normal color
-                        println!("{:4} | {}", line_num, line);
-                    }
-                }
-            }
+            print_snippet(
+                input,
+                *span,
+                &[
+                    "\x1b[33mHint: 'END' commands must be paired with a 'TO' procedure definition:\x1b[0m",
+                    "     | TO procedure_name",
+                    "     |    commands...",
+                    "     | END",
+                ],
+            );
         }
         RSLogoError::InvalidArgument {
             command,
@@ -176,5 +415,115 @@ fn report_error(err: &RSLogoError) {
         RSLogoError::Overflow => {
             println!("Arithmetic overflow occurred");
         }
+        RSLogoError::MisplacedCommand { command, context } => {
+            println!("Error: '{}' is only valid {}", command, context);
+        }
+        RSLogoError::RecursionLimitExceeded { procedure, limit } => {
+            println!(
+                "Error: Recursion limit of {} exceeded while calling '{}'",
+                limit, procedure
+            );
+            println!("Check that the procedure has a base case, or raise --max-depth.");
+        }
+        RSLogoError::ArityMismatch {
+            procedure,
+            expected,
+            got,
+            context,
+        } => {
+            println!(
+                "Error: Procedure '{}' takes {} but was called with {}",
+                procedure, expected, got
+            );
+            println!("  in {}", context);
+        }
+        RSLogoError::IterationLimitExceeded { command, limit } => {
+            println!(
+                "Error: {} loop ran more than {} times without finishing",
+                command, limit
+            );
+            println!("Check that the loop condition can change, or raise --max-iterations.");
+        }
+        RSLogoError::AssertionFailed(condition) => {
+            println!("Error: Assertion failed: {}", condition);
+        }
+        RSLogoError::Timeout(limit) => {
+            println!(
+                "Error: Program was still running after {} seconds",
+                limit.as_secs_f64()
+            );
+            println!("Look for a loop or recursion that never ends, or raise --timeout.");
+        }
+        RSLogoError::IncludeCycle { chain } => {
+            println!("Error: Include cycle detected:");
+            for (i, file) in chain.iter().enumerate() {
+                println!("  {}{}", if i == 0 { "" } else { "-> " }, file);
+            }
+        }
+        RSLogoError::CheckFailed(count) => {
+            println!("Check failed: {} problem(s) found", count);
+        }
+        RSLogoError::RuntimeError { error, input, span } => {
+            let (line, column) = line_and_column(input, span.0);
+            println!(
+                "\x1b[1;31mError on line {}, column {}:\x1b[0m",
+                line, column
+            );
+            report_error(error);
+            print_snippet(input, *span, &[]);
+        }
+    }
+}
+
+// Show the source around span, pointing at the offending text and
+// following it with any hint lines
+fn print_snippet(input: &str, span: (usize, usize), hint: &[&str]) {
+    if input.is_empty() {
+        return;
+    }
+    // A span past the end points just after the last character
+    let mut start = span.0.min(input.len());
+    while !input.is_char_boundary(start) {
+        start -= 1;
+    }
+    let span = (start, span.1);
+    println!("\nRelevant code:");
+    let lines: Vec<&str> = input.lines().collect();
+    let start_line = input[..span.0].matches('\n').count();
+
+    // Print a few lines before and after the error for context
+    let context_lines = 2;
+    let start_idx = start_line.saturating_sub(context_lines);
+    let end_idx = (start_line + context_lines + 1).min(lines.len());
+
+    for (idx, line) in lines[start_idx..end_idx].iter().enumerate() {
+        let line_num = start_idx + idx + 1;
+        if line_num == start_line + 1 {
+            // Error line in red
+            println!("\x1b[31m{:4} | {}\x1b[0m", line_num, line);
+
+            // Print error pointer under the column the span starts at,
+            // stopping at the end of the line for spans that run over
+            // several lines
+            let line_start = input[..span.0].rfind('\n').map_or(0, |n| n + 1);
+            let pointer_offset = (span.0 - line_start).min(line.len());
+            let pointer_len = span.1.min(line.len().saturating_sub(pointer_offset)).max(1);
+            println!(
+                "     | {}\x1b[31m{}\x1b[0m",
+                " ".repeat(pointer_offset),
+                "^".repeat(pointer_len)
+            );
+
+            // Print suggestion
+            if !hint.is_empty() {
+                println!("     | ");
+                for hint_line in hint {
+                    println!("{}", hint_line);
+                }
+            }
+        } else {
+            // Context lines in normal color
+            println!("{:4} | {}", line_num, line);
+        }
     }
 }
@@ -0,0 +1,170 @@
+use crate::rs_error::RSLogoError;
+use crate::rs_turtle::{enlarge, rasterize};
+use image::codecs::gif::{GifEncoder, Repeat};
+use image::{Delay, Frame, RgbaImage};
+use std::fs::File;
+use std::path::Path;
+use unsvg::{Color, Image};
+
+const FRAME_DELAY_MS: u32 = 100;
+
+// Rather than copying the image for every frame, the drawing is recorded
+// as a log of operations, and each frame is rebuilt by replaying part of it
+#[derive(Clone, Copy)]
+enum Stroke {
+    Line {
+        x: i32,
+        y: i32,
+        direction: i32,
+        length: i32,
+        color: Color,
+    },
+    Clear,
+}
+
+pub struct Animation {
+    strokes: Vec<Stroke>,
+    frame_marks: Vec<usize>,
+    // How long each frame is shown, added to by WAIT
+    frame_delays: Vec<u32>,
+    max_frames: usize,
+    // SETSPEED: a frame is captured every this many movements
+    steps_per_frame: usize,
+    steps_since_frame: usize,
+}
+
+impl Animation {
+    pub fn new(max_frames: usize) -> Self {
+        Self {
+            strokes: Vec::new(),
+            frame_marks: Vec::new(),
+            frame_delays: Vec::new(),
+            max_frames,
+            steps_per_frame: 1,
+            steps_since_frame: 0,
+        }
+    }
+
+    // Once the frame budget is spent nothing more is recorded; the final
+    // frame is taken from the finished image instead
+    fn is_full(&self) -> bool {
+        self.frame_marks.len() >= self.max_frames
+    }
+
+    pub fn record_line(&mut self, x: i32, y: i32, direction: i32, length: i32, color: Color) {
+        if !self.is_full() {
+            self.strokes.push(Stroke::Line {
+                x,
+                y,
+                direction,
+                length,
+                color,
+            });
+        }
+    }
+
+    // The same frame budget with nothing recorded yet
+    pub fn restart(&self) -> Self {
+        Self::new(self.max_frames)
+    }
+
+    pub fn record_clear(&mut self) {
+        if !self.is_full() {
+            self.strokes.push(Stroke::Clear);
+        }
+    }
+
+    pub fn set_steps_per_frame(&mut self, steps: usize) {
+        self.steps_per_frame = steps.max(1);
+    }
+
+    // Called after every movement; only each steps_per_frame-th one is
+    // captured
+    pub fn capture_frame(&mut self) {
+        self.steps_since_frame += 1;
+        if self.steps_since_frame >= self.steps_per_frame {
+            self.take_frame();
+        }
+    }
+
+    // Moves that drew nothing new don't get a frame of their own
+    fn take_frame(&mut self) {
+        self.steps_since_frame = 0;
+        if !self.is_full() && self.frame_marks.last() != Some(&self.strokes.len()) {
+            self.frame_marks.push(self.strokes.len());
+            self.frame_delays.push(FRAME_DELAY_MS);
+        }
+    }
+
+    // Keep the drawing as it is now on screen for longer. Without a frame
+    // showing it yet, one is taken first.
+    pub fn hold(&mut self, delay_ms: u32) {
+        self.take_frame();
+        if self.frame_marks.last() == Some(&self.strokes.len()) {
+            if let Some(delay) = self.frame_delays.last_mut() {
+                *delay = delay.saturating_add(delay_ms);
+            }
+        }
+    }
+
+    // The last frame is the finished drawing, already rasterized with
+    // anything unsvg can't draw itself. If the last captured frame already
+    // shows every line, the finished drawing takes its place rather than
+    // following it. Once the recording is full there may be lines it
+    // missed, so the finished drawing always follows.
+    pub fn save_gif(
+        &self,
+        final_pixels: RgbaImage,
+        width: u32,
+        height: u32,
+        scale: u32,
+        path: &Path,
+    ) -> Result<(), RSLogoError> {
+        let mut encoder = GifEncoder::new(File::create(path)?);
+        encoder.set_repeat(Repeat::Infinite).map_err(gif_error)?;
+        let mut marks = self.frame_marks.len();
+        let mut final_delay = FRAME_DELAY_MS;
+        if !self.is_full() && self.frame_marks.last() == Some(&self.strokes.len()) {
+            marks -= 1;
+            final_delay = self.frame_delays[marks];
+        }
+        for (&mark, &delay_ms) in self.frame_marks[..marks].iter().zip(&self.frame_delays) {
+            let frame = self.replay(mark, width, height)?;
+            encoder
+                .encode_frame(to_frame(enlarge(rasterize(&frame)?, scale), delay_ms))
+                .map_err(gif_error)?;
+        }
+        encoder
+            .encode_frame(to_frame(final_pixels, final_delay))
+            .map_err(gif_error)
+    }
+
+    fn replay(&self, count: usize, width: u32, height: u32) -> Result<Image, RSLogoError> {
+        let mut image = Image::new(width, height);
+        for stroke in &self.strokes[..count] {
+            match *stroke {
+                Stroke::Line {
+                    x,
+                    y,
+                    direction,
+                    length,
+                    color,
+                } => {
+                    image
+                        .draw_simple_line(x, y, direction, length, color)
+                        .map_err(|e| RSLogoError::DrawError(e.to_string()))?;
+                }
+                Stroke::Clear => image = Image::new(width, height),
+            }
+        }
+        Ok(image)
+    }
+}
+
+fn to_frame(pixels: RgbaImage, delay_ms: u32) -> Frame {
+    Frame::from_parts(pixels, 0, 0, Delay::from_numer_denom_ms(delay_ms, 1))
+}
+
+fn gif_error(err: image::ImageError) -> RSLogoError {
+    RSLogoError::ImageSaveError(err.to_string())
+}
@@ -1,40 +1,122 @@
 use std::fmt;
+use std::rc::Rc;
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum Value {
     Number(i32),
+    Float(f64),
     String(String),
     Variable(String),
     Boolean(bool),
+    List(Vec<Value>),
 }
 
+// Where a command was written: a byte offset into the source and its length
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Span {
+    pub offset: usize,
+    pub len: usize,
+}
+
+// A nested list of commands. Shared so the interpreter can hold on to a
+// block while it runs without copying it.
+pub type Block = Rc<Vec<Command>>;
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum Command {
     PenUp,
     PenDown,
+    // Switch XOR drawing on or off; drawing the same line twice erases it
+    PenReverse,
+    // Lines drawn until the matching ENDGROUP form one named SVG group
+    BeginGroup(Expression),
+    EndGroup,
     Forward(Expression),
     Back(Expression),
     Left(Expression),
     Right(Expression),
     SetPenColor(Expression),
+    SetPenSize(Expression),
+    SetRGB(Expression, Expression, Expression),
+    BeginFill,
+    EndFill,
     Turn(Expression),
     SetHeading(Expression),
     SetX(Expression),
     SetY(Expression),
+    // Move along one axis without drawing, whatever the pen state
+    JumpX(Expression),
+    JumpY(Expression),
+    Arc(Expression, Expression),
+    // Shift the turtle by dx, dy without turning it
+    MoveBy(Expression, Expression),
+    // Go to a logical position given as a two-item list, such as POS returns
+    SetPos(Expression),
+    Home,
+    ClearScreen,
+    PushTurtle,
+    PopTurtle,
+    ShowTurtle,
+    HideTurtle,
+    // Hold the current animation frame for this many hundredths of a second
+    Wait(Expression),
+    // How many movements make one animation frame
+    SetSpeed(Expression),
+    // Write a value as text at the turtle's position
+    Label(Expression),
+    SetFontSize(Expression),
     Make(Expression, Expression),
     AddAssign(String, Expression),
-    If(Expression, Vec<Command>),
-    While(Expression, Vec<Command>),
+    If(Expression, Block),
+    IfElse(Expression, Block, Block),
+    While(Expression, Block),
+    DoUntil(Block, Expression),
+    For {
+        var: String,
+        from: Expression,
+        to: Expression,
+        step: Expression,
+        body: Block,
+    },
+    ForEach {
+        var: String,
+        list: Expression,
+        body: Block,
+    },
     Expression(Box<Expression>),
     ProcedureDefinition {
         name: String,
         parameters: Vec<String>,
-        body: Vec<Command>,
+        // A final :name... parameter that collects any remaining arguments
+        // into a list
+        rest: Option<String>,
+        body: Block,
     },
     ProcedureCall {
         name: String,
         arguments: Vec<Expression>,
     },
+    Output(Expression),
+    Stop,
+    // Skip the rest of the innermost loop's body and start its next iteration
+    Continue,
+    // Leave the innermost loop straight away
+    Break,
+    // Forget every variable and procedure and start again on a blank canvas
+    Reset,
+    Local(String),
+    Global(String),
+    // Forget a procedure so calling it is an error again
+    Erase(String),
+    // Write a value to stdout, whatever the verbosity
+    Print(Expression),
+    // Fail with an error unless the condition holds
+    Assert(Expression),
+    // The parser wraps every command in its source location so runtime
+    // errors can point back at it
+    Located(Span, Box<Command>),
+    // Spliced out by parse_program_with_includes before the program runs
+    Include(String),
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -43,12 +125,18 @@ pub enum Operator {
     Subtract,
     Multiply,
     Divide,
+    Power,
     Equal,
     NotEqual,
     GreaterThan,
     LessThan,
+    GreaterEqual,
+    LessEqual,
     And,
     Or,
+    Xor,
+    Max,
+    Min,
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -56,20 +144,160 @@ pub enum Expression {
     Value(Value),
     BinaryOp(Operator, Box<Expression>, Box<Expression>),
     Query(String),
+    Call(String, Vec<Expression>),
+    Function(String, Vec<Expression>),
 }
 
 #[derive(Debug, PartialEq)]
 pub struct Program {
     pub commands: Vec<Command>,
+    pub source: String,
+}
+
+impl Command {
+    // The command itself, looking through any source location around it
+    pub fn unlocated(&self) -> &Command {
+        match self {
+            Command::Located(_, inner) => inner.unlocated(),
+            _ => self,
+        }
+    }
+
+    // Expressions evaluated directly by this command (not those inside nested blocks)
+    pub fn expressions(&self) -> Vec<&Expression> {
+        match self {
+            Command::PenUp
+            | Command::PenDown
+            | Command::PenReverse
+            | Command::EndGroup
+            | Command::BeginFill
+            | Command::EndFill
+            | Command::Home
+            | Command::ClearScreen
+            | Command::PushTurtle
+            | Command::PopTurtle
+            | Command::ShowTurtle
+            | Command::HideTurtle
+            | Command::ProcedureDefinition { .. }
+            | Command::Stop
+            | Command::Continue
+            | Command::Break
+            | Command::Reset
+            | Command::Local(_)
+            | Command::Global(_)
+            | Command::Erase(_)
+            | Command::Include(_) => Vec::new(),
+            Command::Forward(e)
+            | Command::Back(e)
+            | Command::Left(e)
+            | Command::Right(e)
+            | Command::SetPenColor(e)
+            | Command::SetPenSize(e)
+            | Command::Turn(e)
+            | Command::SetHeading(e)
+            | Command::SetX(e)
+            | Command::SetY(e)
+            | Command::SetPos(e)
+            | Command::BeginGroup(e)
+            | Command::JumpX(e)
+            | Command::JumpY(e)
+            | Command::AddAssign(_, e)
+            | Command::If(e, _)
+            | Command::IfElse(e, _, _)
+            | Command::While(e, _)
+            | Command::DoUntil(_, e)
+            | Command::Output(e)
+            | Command::Print(e)
+            | Command::Wait(e)
+            | Command::SetSpeed(e)
+            | Command::Label(e)
+            | Command::SetFontSize(e)
+            | Command::Assert(e)
+            | Command::ForEach { list: e, .. } => vec![e],
+            Command::Expression(e) => vec![e.as_ref()],
+            Command::SetRGB(r, g, b) => vec![r, g, b],
+            Command::Arc(a, b) | Command::MoveBy(a, b) | Command::Make(a, b) => vec![a, b],
+            Command::For { from, to, step, .. } => vec![from, to, step],
+            Command::ProcedureCall { arguments, .. } => arguments.iter().collect(),
+            Command::Located(_, inner) => inner.expressions(),
+        }
+    }
+
+    // Nested command blocks, including a procedure definition's body
+    pub fn blocks(&self) -> Vec<&[Command]> {
+        match self {
+            Command::If(_, body)
+            | Command::While(_, body)
+            | Command::DoUntil(body, _)
+            | Command::For { body, .. }
+            | Command::ForEach { body, .. }
+            | Command::ProcedureDefinition { body, .. } => vec![body.as_slice()],
+            Command::IfElse(_, then_body, else_body) => {
+                vec![then_body.as_slice(), else_body.as_slice()]
+            }
+            Command::Located(_, inner) => inner.blocks(),
+            _ => Vec::new(),
+        }
+    }
+
+    // Mutable counterpart of blocks(); keep the two in step
+    pub fn blocks_mut(&mut self) -> Vec<&mut [Command]> {
+        match self {
+            Command::If(_, body)
+            | Command::While(_, body)
+            | Command::DoUntil(body, _)
+            | Command::For { body, .. }
+            | Command::ForEach { body, .. }
+            | Command::ProcedureDefinition { body, .. } => {
+                vec![Rc::make_mut(body).as_mut_slice()]
+            }
+            Command::IfElse(_, then_body, else_body) => vec![
+                Rc::make_mut(then_body).as_mut_slice(),
+                Rc::make_mut(else_body).as_mut_slice(),
+            ],
+            Command::Located(_, inner) => inner.blocks_mut(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+impl Expression {
+    // Visit this expression and every sub-expression, outermost first
+    pub fn visit<'a>(&'a self, f: &mut impl FnMut(&'a Expression)) {
+        f(self);
+        match self {
+            Expression::Value(_) | Expression::Query(_) => {}
+            Expression::BinaryOp(_, left, right) => {
+                left.visit(f);
+                right.visit(f);
+            }
+            Expression::Function(_, arguments) | Expression::Call(_, arguments) => {
+                for arg in arguments {
+                    arg.visit(f);
+                }
+            }
+        }
+    }
 }
 
 impl fmt::Display for Value {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Value::Number(n) => write!(f, "{}", n),
+            Value::Float(x) => write!(f, "{}", x),
             Value::String(s) => write!(f, "{}", s),
             Value::Variable(v) => write!(f, ":{}", v),
             Value::Boolean(b) => write!(f, "{}", if *b { "TRUE" } else { "FALSE" }),
+            Value::List(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            }
         }
     }
 }
@@ -78,16 +306,38 @@ impl fmt::Display for Command {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Command::PenUp => write!(f, "PENUP"),
+            Command::PenReverse => write!(f, "PENREVERSE"),
+            Command::BeginGroup(v) => write!(f, "GROUP {}", v),
+            Command::EndGroup => write!(f, "ENDGROUP"),
             Command::PenDown => write!(f, "PENDOWN"),
             Command::Forward(v) => write!(f, "FORWARD {}", v),
             Command::Back(v) => write!(f, "BACK {}", v),
             Command::Left(v) => write!(f, "LEFT {}", v),
             Command::Right(v) => write!(f, "RIGHT {}", v),
             Command::SetPenColor(v) => write!(f, "SETPENCOLOR {}", v),
+            Command::SetPenSize(v) => write!(f, "SETPENSIZE {}", v),
+            Command::SetRGB(r, g, b) => write!(f, "SETRGB {} {} {}", r, g, b),
+            Command::BeginFill => write!(f, "BEGINFILL"),
+            Command::EndFill => write!(f, "ENDFILL"),
             Command::Turn(v) => write!(f, "TURN {}", v),
             Command::SetHeading(v) => write!(f, "SETHEADING {}", v),
             Command::SetX(v) => write!(f, "SETX {}", v),
             Command::SetY(v) => write!(f, "SETY {}", v),
+            Command::JumpX(v) => write!(f, "JUMPX {}", v),
+            Command::JumpY(v) => write!(f, "JUMPY {}", v),
+            Command::Arc(angle, radius) => write!(f, "ARC {} {}", angle, radius),
+            Command::MoveBy(dx, dy) => write!(f, "MOVEBY {} {}", dx, dy),
+            Command::SetPos(v) => write!(f, "SETPOS {}", v),
+            Command::Home => write!(f, "HOME"),
+            Command::ClearScreen => write!(f, "CLEARSCREEN"),
+            Command::PushTurtle => write!(f, "PUSHTURTLE"),
+            Command::PopTurtle => write!(f, "POPTURTLE"),
+            Command::ShowTurtle => write!(f, "SHOWTURTLE"),
+            Command::HideTurtle => write!(f, "HIDETURTLE"),
+            Command::Wait(v) => write!(f, "WAIT {}", v),
+            Command::SetSpeed(v) => write!(f, "SETSPEED {}", v),
+            Command::Label(v) => write!(f, "LABEL {}", v),
+            Command::SetFontSize(v) => write!(f, "SETFONTSIZE {}", v),
             Command::Make(expr1, expr2) => write!(f, "MAKE {} {}", expr1, expr2),
             Command::AddAssign(name, v) => write!(f, "ADDASSIGN {} {}", name, v),
             Command::If(condition, body) => {
@@ -100,6 +350,23 @@ impl fmt::Display for Command {
                 }
                 write!(f, "]")
             }
+            Command::IfElse(condition, then_body, else_body) => {
+                write!(f, "IFELSE {} [", condition)?;
+                for (i, cmd) in then_body.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?
+                    }
+                    write!(f, "{}", cmd)?;
+                }
+                write!(f, "] [")?;
+                for (i, cmd) in else_body.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?
+                    }
+                    write!(f, "{}", cmd)?;
+                }
+                write!(f, "]")
+            }
             Command::While(condition, body) => {
                 write!(f, "WHILE {} [", condition)?;
                 for (i, cmd) in body.iter().enumerate() {
@@ -110,18 +377,58 @@ impl fmt::Display for Command {
                 }
                 write!(f, "]")
             }
+            Command::DoUntil(body, condition) => {
+                write!(f, "DO [")?;
+                for (i, cmd) in body.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?
+                    }
+                    write!(f, "{}", cmd)?;
+                }
+                write!(f, "] UNTIL {}", condition)
+            }
+            Command::For {
+                var,
+                from,
+                to,
+                step,
+                body,
+            } => {
+                write!(f, "FOR :{} {} {} {} [", var, from, to, step)?;
+                for (i, cmd) in body.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?
+                    }
+                    write!(f, "{}", cmd)?;
+                }
+                write!(f, "]")
+            }
+            Command::ForEach { var, list, body } => {
+                write!(f, "FOREACH :{} {} [", var, list)?;
+                for (i, cmd) in body.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?
+                    }
+                    write!(f, "{}", cmd)?;
+                }
+                write!(f, "]")
+            }
             Command::Expression(expr) => write!(f, "{}", expr),
             Command::ProcedureDefinition {
                 name,
                 parameters,
+                rest,
                 body,
             } => {
                 write!(f, "TO {} ", name)?;
                 for param in parameters {
                     write!(f, "{} ", param)?;
                 }
+                if let Some(rest) = rest {
+                    write!(f, "{}... ", rest)?;
+                }
                 write!(f, "[")?;
-                for cmd in body {
+                for cmd in body.iter() {
                     write!(f, " {}", cmd)?;
                 }
                 write!(f, "] END")
@@ -133,6 +440,18 @@ impl fmt::Display for Command {
                 }
                 Ok(())
             }
+            Command::Output(v) => write!(f, "OUTPUT {}", v),
+            Command::Stop => write!(f, "STOP"),
+            Command::Continue => write!(f, "CONTINUE"),
+            Command::Break => write!(f, "BREAK"),
+            Command::Reset => write!(f, "RESET"),
+            Command::Local(name) => write!(f, "LOCAL \"{}", name),
+            Command::Global(name) => write!(f, "GLOBAL \"{}", name),
+            Command::Erase(name) => write!(f, "ERASE \"{}", name),
+            Command::Print(v) => write!(f, "PRINT {}", v),
+            Command::Assert(v) => write!(f, "ASSERT {}", v),
+            Command::Located(_, inner) => write!(f, "{}", inner),
+            Command::Include(path) => write!(f, "INCLUDE \"{}", path),
         }
     }
 }
@@ -141,8 +460,22 @@ impl fmt::Display for Expression {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Expression::Value(v) => write!(f, "{}", v),
-            Expression::BinaryOp(left, op, right) => write!(f, "({} {} {})", left, op, right),
+            Expression::BinaryOp(op, left, right) => write!(f, "({} {} {})", op, left, right),
             Expression::Query(q) => write!(f, "{}", q),
+            Expression::Function(name, arguments) => {
+                write!(f, "{}", name)?;
+                for arg in arguments {
+                    write!(f, " {}", arg)?;
+                }
+                Ok(())
+            }
+            Expression::Call(name, arguments) => {
+                write!(f, "CALL {}", name)?;
+                for arg in arguments {
+                    write!(f, " {}", arg)?;
+                }
+                Ok(())
+            }
         }
     }
 }
@@ -154,12 +487,18 @@ impl fmt::Display for Operator {
             Operator::Subtract => write!(f, "-"),
             Operator::Multiply => write!(f, "*"),
             Operator::Divide => write!(f, "/"),
+            Operator::Power => write!(f, "POWER"),
             Operator::Equal => write!(f, "EQ"),
             Operator::NotEqual => write!(f, "NE"),
             Operator::GreaterThan => write!(f, "GT"),
             Operator::LessThan => write!(f, "LT"),
+            Operator::GreaterEqual => write!(f, "GE"),
+            Operator::LessEqual => write!(f, "LE"),
             Operator::And => write!(f, "AND"),
             Operator::Or => write!(f, "OR"),
+            Operator::Xor => write!(f, "XOR"),
+            Operator::Max => write!(f, "MAX"),
+            Operator::Min => write!(f, "MIN"),
         }
     }
 }
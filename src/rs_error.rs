@@ -1,4 +1,5 @@
 use std::fmt;
+use std::time::Duration;
 
 #[derive(Debug)]
 pub enum RSLogoError {
@@ -32,6 +33,185 @@ pub enum RSLogoError {
         got: String,
     },
     Overflow,
+    MisplacedCommand {
+        command: String,
+        context: String,
+    },
+    RecursionLimitExceeded {
+        procedure: String,
+        limit: usize,
+    },
+    ArityMismatch {
+        procedure: String,
+        // "2 arguments", or "at least 2 arguments" with a rest parameter
+        expected: String,
+        got: usize,
+        context: String,
+    },
+    // A runtime error tagged with the command that raised it. The span has
+    // the same meaning as in ParseError.
+    RuntimeError {
+        error: Box<RSLogoError>,
+        input: String,
+        span: (usize, usize),
+    },
+    IterationLimitExceeded {
+        command: String,
+        limit: usize,
+    },
+    // Each file in the chain includes the next; the last is the first again
+    IncludeCycle {
+        chain: Vec<String>,
+    },
+    // --check found this many problems, each already reported
+    CheckFailed(usize),
+    // An ASSERT whose condition was false, shown as it was written
+    AssertionFailed(String),
+    // The program was still running when --timeout ran out
+    Timeout(Duration),
+}
+
+impl RSLogoError {
+    // Tag an error with the innermost command it came from; the source text
+    // is filled in later by with_source
+    pub fn located(self, offset: usize, len: usize) -> Self {
+        match self {
+            RSLogoError::RuntimeError { .. } => self,
+            _ => RSLogoError::RuntimeError {
+                error: Box::new(self),
+                input: String::new(),
+                span: (offset, len),
+            },
+        }
+    }
+
+    pub fn with_source(self, source: &str) -> Self {
+        match self {
+            RSLogoError::RuntimeError { error, span, .. } => RSLogoError::RuntimeError {
+                error,
+                input: source.to_string(),
+                span,
+            },
+            _ => self,
+        }
+    }
+
+    // The fields of this error in a uniform shape, for tools that can't
+    // parse the human-readable report
+    pub fn report(&self) -> ErrorReport {
+        match self {
+            RSLogoError::RuntimeError { error, input, span } => {
+                let mut report = error.report();
+                report.span = Some(*span);
+                if !input.is_empty() {
+                    report.position = Some(line_and_column(input, span.0));
+                }
+                report
+            }
+            RSLogoError::ParseError {
+                input,
+                span,
+                message,
+            } => ErrorReport {
+                kind: self.kind(),
+                message: message.clone(),
+                span: Some(*span),
+                position: Some(line_and_column(input, span.0)),
+            },
+            _ => ErrorReport {
+                kind: self.kind(),
+                message: self.to_string(),
+                span: None,
+                position: None,
+            },
+        }
+    }
+
+    fn kind(&self) -> &'static str {
+        match self {
+            RSLogoError::ParseError { .. } => "parse_error",
+            RSLogoError::IOError(_) => "io_error",
+            RSLogoError::InvalidArgument { .. } => "invalid_argument",
+            RSLogoError::DrawError(_) => "draw_error",
+            RSLogoError::ImageSaveError(_) => "image_save_error",
+            RSLogoError::UndefinedVariable { .. } => "undefined_variable",
+            RSLogoError::StackUnderflow => "stack_underflow",
+            RSLogoError::DivisionByZero => "division_by_zero",
+            RSLogoError::TypeMismatch => "type_mismatch",
+            RSLogoError::InvalidExpression(_) => "invalid_expression",
+            RSLogoError::InvalidOperator(_) => "invalid_operator",
+            RSLogoError::UnexpectedValue { .. } => "unexpected_value",
+            RSLogoError::Overflow => "overflow",
+            RSLogoError::MisplacedCommand { .. } => "misplaced_command",
+            RSLogoError::RecursionLimitExceeded { .. } => "recursion_limit_exceeded",
+            RSLogoError::ArityMismatch { .. } => "arity_mismatch",
+            RSLogoError::RuntimeError { error, .. } => error.kind(),
+            RSLogoError::IterationLimitExceeded { .. } => "iteration_limit_exceeded",
+            RSLogoError::IncludeCycle { .. } => "include_cycle",
+            RSLogoError::CheckFailed(_) => "check_failed",
+            RSLogoError::AssertionFailed(_) => "assertion_failed",
+            RSLogoError::Timeout(_) => "timeout",
+        }
+    }
+}
+
+// What --error-format json prints. A runtime error reports the kind and
+// message of the error inside it, at the location it was tagged with.
+#[derive(Debug)]
+pub struct ErrorReport {
+    pub kind: &'static str,
+    pub message: String,
+    // Byte offset and length into the source
+    pub span: Option<(usize, usize)>,
+    // 1-based line and column of the span's start
+    pub position: Option<(usize, usize)>,
+}
+
+impl ErrorReport {
+    // A single-line JSON object; fields without a value are null
+    pub fn to_json(&self) -> String {
+        let (line, column) = match self.position {
+            Some((line, column)) => (line.to_string(), column.to_string()),
+            None => ("null".to_string(), "null".to_string()),
+        };
+        let span = match self.span {
+            Some((offset, len)) => format!("{{\"offset\":{},\"len\":{}}}", offset, len),
+            None => "null".to_string(),
+        };
+        format!(
+            "{{\"kind\":{},\"message\":{},\"line\":{},\"column\":{},\"span\":{}}}",
+            json_string(self.kind),
+            json_string(&self.message),
+            line,
+            column,
+            span
+        )
+    }
+}
+
+fn json_string(text: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+// 1-based line and column of a byte offset into the source
+pub fn line_and_column(input: &str, offset: usize) -> (usize, usize) {
+    let before = &input[..offset.min(input.len())];
+    let line = before.matches('\n').count() + 1;
+    let column = before.len() - before.rfind('\n').map_or(0, |n| n + 1) + 1;
+    (line, column)
 }
 
 impl fmt::Display for RSLogoError {
@@ -77,6 +257,47 @@ impl fmt::Display for RSLogoError {
                 expected, got
             ),
             RSLogoError::Overflow => write!(f, "Arithmetic overflow occurred"),
+            RSLogoError::MisplacedCommand { command, context } => {
+                write!(f, "'{}' is only valid {}", command, context)
+            }
+            RSLogoError::RecursionLimitExceeded { procedure, limit } => write!(
+                f,
+                "Recursion limit of {} exceeded while calling '{}'",
+                limit, procedure
+            ),
+            RSLogoError::ArityMismatch {
+                procedure,
+                expected,
+                got,
+                context,
+            } => write!(
+                f,
+                "Procedure '{}' takes {} but was called with {} in {}",
+                procedure, expected, got, context
+            ),
+            RSLogoError::RuntimeError { error, input, span } => {
+                let (line, column) = line_and_column(input, span.0);
+                write!(f, "line {}, column {}: {}", line, column, error)
+            }
+            RSLogoError::IterationLimitExceeded { command, limit } => {
+                write!(
+                    f,
+                    "{} loop exceeded the limit of {} iterations",
+                    command, limit
+                )
+            }
+            RSLogoError::IncludeCycle { chain } => {
+                write!(f, "Include cycle: {}", chain.join(" -> "))
+            }
+            RSLogoError::CheckFailed(count) => write!(f, "Check found {} problem(s)", count),
+            RSLogoError::AssertionFailed(condition) => {
+                write!(f, "Assertion failed: {}", condition)
+            }
+            RSLogoError::Timeout(limit) => write!(
+                f,
+                "Execution exceeded the time limit of {} seconds",
+                limit.as_secs_f64()
+            ),
         }
     }
 }
@@ -0,0 +1,91 @@
+use crate::rs_label::escape_xml;
+use std::collections::HashMap;
+
+// GROUP and ENDGROUP. unsvg writes each line it draws as its own <path>
+// and knows nothing of groups, so each group's start and end is recorded
+// as the number of lines drawn so far, and the <g> tags are added to the
+// SVG on the way out. Raster output has no use for them.
+enum GroupEvent {
+    Open(String),
+    Close,
+}
+
+pub struct Groups {
+    // In the order they happened, with the line count at the time
+    events: Vec<(usize, GroupEvent)>,
+    // Names of the groups still open, innermost last
+    open: Vec<String>,
+}
+
+impl Groups {
+    pub fn new() -> Self {
+        Self {
+            events: Vec::new(),
+            open: Vec::new(),
+        }
+    }
+
+    pub fn begin(&mut self, name: String, lines: usize) {
+        self.events.push((lines, GroupEvent::Open(name.clone())));
+        self.open.push(name);
+    }
+
+    // False if there was no group to end
+    pub fn end(&mut self, lines: usize) -> bool {
+        if self.open.pop().is_none() {
+            return false;
+        }
+        self.events.push((lines, GroupEvent::Close));
+        true
+    }
+
+    // The drawing was wiped, so only the groups still open are left, and
+    // they now start at its first line
+    pub fn clear(&mut self) {
+        self.events = self
+            .open
+            .iter()
+            .map(|name| (0, GroupEvent::Open(name.clone())))
+            .collect();
+    }
+
+    // Wrap each group's lines in a <g>. A name used more than once gets a
+    // -2, -3, ... suffix so ids stay unique, and groups still open at the
+    // end are closed after the last line.
+    pub fn add_to_svg(&self, svg: &str) -> String {
+        let end = match svg.rfind("</svg>") {
+            Some(end) => end,
+            None => return svg.to_string(),
+        };
+        // The first <path> is the rectangle unsvg fills the canvas with;
+        // the lines follow it
+        let starts: Vec<usize> = svg.match_indices("<path").skip(1).map(|(i, _)| i).collect();
+        let mut uses: HashMap<&str, usize> = HashMap::new();
+        let mut grouped = String::new();
+        let mut copied = 0;
+        for (lines, event) in &self.events {
+            let at = starts.get(*lines).copied().unwrap_or(end);
+            grouped.push_str(&svg[copied..at]);
+            copied = at;
+            match event {
+                GroupEvent::Open(name) => {
+                    let count = uses.entry(name).or_insert(0);
+                    *count += 1;
+                    let id = if *count == 1 {
+                        name.clone()
+                    } else {
+                        format!("{}-{}", name, count)
+                    };
+                    grouped.push_str(&format!("<g id=\"{}\">\n", escape_xml(&id)));
+                }
+                GroupEvent::Close => grouped.push_str("</g>\n"),
+            }
+        }
+        grouped.push_str(&svg[copied..end]);
+        for _ in &self.open {
+            grouped.push_str("</g>\n");
+        }
+        grouped.push_str(&svg[end..]);
+        grouped
+    }
+}
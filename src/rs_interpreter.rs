@@ -1,112 +1,782 @@
-use crate::rs_ast::{Command, Expression, Program, Value};
-use crate::rs_error::RSLogoError;
-use crate::rs_procedure::ProcedureManager;
+use crate::rs_ast::{Block, Command, Expression, Operator, Program, Value};
+use crate::rs_error::{line_and_column, RSLogoError};
+use crate::rs_operators::{self, Numeric};
+use crate::rs_procedure::{check_arity, ProcedureManager};
+use crate::rs_random::Random;
 use crate::rs_stack::Stack;
-use crate::rs_turtle::Turtle;
+use crate::rs_turtle::{EdgeMode, Origin, Turtle, TurtleSnapshot, YAxis};
 use crate::rs_variables::VariableManager;
 use std::path::Path;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use unsvg::Color;
+
+pub const DEFAULT_MAX_ITERATIONS: usize = 1_000_000;
+
+// Reading the clock on every frame would dominate tight loops, so the
+// timeout is only checked once per this many steps
+const STEPS_PER_TIME_CHECK: usize = 1024;
+
+// How control leaves a command: normally, by unwinding the current
+// procedure, or by skipping the rest of the innermost loop's body or the
+// loop itself
+#[derive(Debug, PartialEq)]
+enum Flow {
+    Normal,
+    Output(Value),
+    Stop,
+    Continue,
+    Break,
+}
+
+// Work still to be done. Nested blocks and procedure calls push frames here
+// instead of recursing, so how deeply a program nests is bounded by the heap
+// rather than the native stack.
+enum Frame {
+    // A block and the index of the next command to run in it
+    Block {
+        commands: Block,
+        next: usize,
+    },
+    // Loops sit under their body and are revisited each time it finishes
+    While {
+        condition: Expression,
+        body: Block,
+        iterations: usize,
+    },
+    DoUntil {
+        body: Block,
+        condition: Expression,
+        iterations: usize,
+    },
+    For {
+        var: String,
+        current: Value,
+        limit: f64,
+        step_value: Value,
+        step_amount: f64,
+        body: Block,
+        iterations: usize,
+    },
+    ForEach {
+        var: String,
+        items: Vec<Value>,
+        next: usize,
+        body: Block,
+        iterations: usize,
+    },
+    // The scope of a procedure call, left once its body finishes
+    Call,
+}
 
 pub struct Interpreter {
     turtle: Turtle,
     variables: VariableManager,
     stack: Stack,
     procedures: ProcedureManager,
+    random: Random,
+    // What set_seed, set_pen_color and set_pen_down were given, so reset
+    // starts the next program the same way
+    seed: u64,
+    start_pen_color: Option<Color>,
+    start_pen_down: bool,
+    max_iterations: usize,
+    // Iterations started by each active loop, innermost last, for REPCOUNT
+    loop_counters: Vec<usize>,
+    timeout: Option<Duration>,
+    // When the current execute has to be finished by, if there is a timeout
+    deadline: Option<Instant>,
+    steps: usize,
+    // With --trace, every command is printed to stderr before it runs
+    trace_steps: bool,
+    // With --warn-offscreen, a command that leaves the turtle off the
+    // canvas is reported on stderr
+    warn_offscreen: bool,
+    // Kept to give traces and warnings a line number
+    source: String,
 }
 
 impl Interpreter {
     pub fn new(width: u32, height: u32) -> Self {
-        println!(
+        trace!(
             "Creating new interpreter with dimensions {}x{}",
-            width, height
+            width,
+            height
         );
         Self {
             turtle: Turtle::new(width, height),
             variables: VariableManager::new(),
             stack: Stack::new(),
             procedures: ProcedureManager::new(),
+            random: Random::new(0),
+            seed: 0,
+            start_pen_color: None,
+            start_pen_down: false,
+            max_iterations: DEFAULT_MAX_ITERATIONS,
+            loop_counters: Vec::new(),
+            timeout: None,
+            deadline: None,
+            steps: 0,
+            trace_steps: false,
+            warn_offscreen: false,
+            source: String::new(),
+        }
+    }
+
+    pub fn set_max_depth(&mut self, max_depth: usize) {
+        self.procedures.set_max_depth(max_depth);
+    }
+
+    // Cap on how many times a single WHILE, DO...UNTIL or FOR runs its body
+    pub fn set_max_iterations(&mut self, max_iterations: usize) {
+        self.max_iterations = max_iterations;
+    }
+
+    // Wall-clock limit on each call to execute
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = Some(timeout);
+    }
+
+    pub fn set_palette(&mut self, palette: Vec<Color>) {
+        self.turtle.set_palette(palette);
+    }
+
+    // The pen color the program starts with, white unless --pen-color is given
+    pub fn set_pen_color(&mut self, color: Color) {
+        self.start_pen_color = Some(color);
+        self.turtle.set_color(color);
+    }
+
+    // The pen state the program starts with; up unless --pen-down is given
+    pub fn set_pen_down(&mut self, down: bool) {
+        self.start_pen_down = down;
+        if down {
+            self.turtle.pen_down();
+        } else {
+            self.turtle.pen_up();
+        }
+    }
+
+    pub fn set_background(&mut self, color: Color) -> Result<(), RSLogoError> {
+        self.turtle.set_background(color)
+    }
+
+    pub fn enable_animation(&mut self, max_frames: usize) {
+        self.turtle.enable_animation(max_frames);
+    }
+
+    pub fn enable_path_recording(&mut self) {
+        self.turtle.enable_path_recording();
+    }
+
+    pub fn save_path(&self, path: &Path) -> Result<(), RSLogoError> {
+        self.turtle.save_path(path)
+    }
+
+    pub fn set_scale(&mut self, scale: u32) -> Result<(), RSLogoError> {
+        self.turtle.set_scale(scale)
+    }
+
+    pub fn set_edge_mode(&mut self, mode: EdgeMode) {
+        self.turtle.set_edge_mode(mode);
+    }
+
+    pub fn set_coordinate_system(&mut self, origin: Origin, y_axis: YAxis) {
+        self.turtle.set_coordinate_system(origin, y_axis);
+    }
+
+    pub fn set_jpeg_quality(&mut self, quality: u8) {
+        self.turtle.set_jpeg_quality(quality);
+    }
+
+    pub fn set_trace_steps(&mut self, trace_steps: bool) {
+        self.trace_steps = trace_steps;
+    }
+
+    pub fn set_warn_offscreen(&mut self, warn_offscreen: bool) {
+        self.warn_offscreen = warn_offscreen;
+    }
+
+    // Reseed RANDOM so repeated runs can reproduce the same image
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+        self.random = Random::new(seed);
+    }
+
+    // Forget everything a program did, so the same interpreter can run
+    // another from scratch: variables, procedures and the stack are
+    // emptied, RANDOM is reseeded and the turtle starts again on a blank
+    // canvas. Limits and output settings given to the setters are kept.
+    pub fn reset(&mut self) -> Result<(), RSLogoError> {
+        trace!("Resetting interpreter");
+        self.variables = VariableManager::new();
+        self.stack = Stack::new();
+        self.procedures.clear();
+        self.random = Random::new(self.seed);
+        self.loop_counters.clear();
+        self.turtle.reset()?;
+        self.set_pen_down(self.start_pen_down);
+        if let Some(color) = self.start_pen_color {
+            self.turtle.set_color(color);
         }
+        Ok(())
     }
 
     pub fn execute(&mut self, program: &Program) -> Result<(), RSLogoError> {
-        println!("Executing program with {} commands", program.commands.len());
-        for (i, command) in program.commands.iter().enumerate() {
-            println!("Executing command {}: {:?}", i + 1, command);
-            self.execute_command(command)?;
+        trace!("Executing program with {} commands", program.commands.len());
+        let mut cursor = 0;
+        while self.step(program, &mut cursor)? {}
+        trace!("Program execution completed");
+        Ok(())
+    }
+
+    // Run the top-level command at cursor and move the cursor past it, even
+    // if it fails, in which case the calls and loops it was inside are
+    // exited. False once there is nothing left to run. Stepping from 0
+    // starts the program, checking its calls and the timeout from there, so
+    // stepping through to the end is the same as one execute.
+    pub fn step(&mut self, program: &Program, cursor: &mut usize) -> Result<bool, RSLogoError> {
+        let command = match program.commands.get(*cursor) {
+            Some(command) => command,
+            None => return Ok(false),
+        };
+        if *cursor == 0 {
+            check_arity(&program.commands).map_err(|err| err.with_source(&program.source))?;
+            self.deadline = self.timeout.map(|timeout| Instant::now() + timeout);
+            if self.trace_steps || self.warn_offscreen {
+                self.source = program.source.clone();
+            }
         }
-        println!("Program execution completed");
+        *cursor += 1;
+        self.run_command(command)
+            .map_err(|err| err.with_source(&program.source))?;
+        Ok(true)
+    }
+
+    // Run a single command on its own, with the timeout counted from now.
+    // Blocks and procedure calls inside it run to the end.
+    pub fn execute_one(&mut self, command: &Command) -> Result<(), RSLogoError> {
+        self.deadline = self.timeout.map(|timeout| Instant::now() + timeout);
+        self.run_command(command)
+    }
+
+    fn run_command(&mut self, command: &Command) -> Result<(), RSLogoError> {
+        let commands = Rc::new(vec![command.clone()]);
+        self.run(vec![Frame::Block { commands, next: 0 }])?;
         Ok(())
     }
 
-    pub fn save_image(&self, image_path: &Path) -> Result<(), RSLogoError> {
-        println!("Saving image to {:?}", image_path);
+    pub fn svg_string(&self) -> Result<String, RSLogoError> {
+        self.turtle.to_svg_string()
+    }
+
+    pub fn turtle(&self) -> &Turtle {
+        &self.turtle
+    }
+
+    pub fn turtle_state(&self) -> TurtleSnapshot {
+        self.turtle.snapshot()
+    }
+
+    pub fn save_image(&mut self, image_path: &Path) -> Result<(), RSLogoError> {
+        trace!("Saving image to {:?}", image_path);
         self.turtle.save_image(image_path)
     }
 
-    fn execute_command(&mut self, command: &Command) -> Result<(), RSLogoError> {
+    // Run frames until none are left. OUTPUT and STOP unwind to the innermost
+    // procedure call; with no call left to catch them they are handed back
+    // to whoever started the run.
+    fn run(&mut self, mut frames: Vec<Frame>) -> Result<Flow, RSLogoError> {
+        let loop_depth = self.loop_counters.len();
+        while let Some(frame) = frames.pop() {
+            let result = self
+                .check_deadline()
+                .and_then(|_| self.run_frame(frame, &mut frames));
+            let flow = match result {
+                Ok(flow) => flow,
+                Err(err) => {
+                    let err = locate_error(&frames, err);
+                    self.unwind(frames, loop_depth);
+                    return Err(err);
+                }
+            };
+            if flow == Flow::Normal {
+                continue;
+            }
+            // CONTINUE and BREAK only ever run inside a loop, so dropping the
+            // blocks above it leaves the loop frame to start its next
+            // iteration. BREAK drops the loop frame too.
+            if flow == Flow::Continue || flow == Flow::Break {
+                while let Some(Frame::Block { .. }) = frames.last() {
+                    frames.pop();
+                }
+                if flow == Flow::Break {
+                    frames.pop();
+                    self.loop_counters.pop();
+                }
+                continue;
+            }
+            loop {
+                match frames.pop() {
+                    Some(Frame::Call) => {
+                        self.leave_procedure();
+                        break;
+                    }
+                    Some(
+                        Frame::While { .. }
+                        | Frame::DoUntil { .. }
+                        | Frame::For { .. }
+                        | Frame::ForEach { .. },
+                    ) => {
+                        self.loop_counters.pop();
+                    }
+                    Some(Frame::Block { .. }) => {}
+                    None => return Ok(flow),
+                }
+            }
+        }
+        Ok(Flow::Normal)
+    }
+
+    // Leave every procedure call and loop a failed run was inside, so the
+    // next command starts from the top level again. A loop whose condition
+    // failed is no longer among the frames, so counters are dropped back to
+    // where the run started instead.
+    fn unwind(&mut self, frames: Vec<Frame>, loop_depth: usize) {
+        for frame in frames.into_iter().rev() {
+            if let Frame::Call = frame {
+                self.leave_procedure();
+            }
+        }
+        self.loop_counters.truncate(loop_depth);
+    }
+
+    fn run_frame(&mut self, frame: Frame, frames: &mut Vec<Frame>) -> Result<Flow, RSLogoError> {
+        match frame {
+            Frame::Block { commands, next } => {
+                if next < commands.len() {
+                    frames.push(Frame::Block {
+                        commands: Rc::clone(&commands),
+                        next: next + 1,
+                    });
+                    let command = &commands[next];
+                    if self.trace_steps {
+                        self.trace_command(command);
+                    }
+                    if !self.warn_offscreen {
+                        return self.execute_command(command, frames);
+                    }
+                    // Commands with a body only push frames, so any move
+                    // seen here was made by this command itself
+                    let before = (self.turtle.get_x(), self.turtle.get_y());
+                    let flow = self.execute_command(command, frames)?;
+                    let after = (self.turtle.get_x(), self.turtle.get_y());
+                    if after != before && self.turtle.is_offscreen() {
+                        eprintln!(
+                            "Warning: line {}: '{}' left the turtle off the canvas at x={} y={}",
+                            self.command_line(command),
+                            first_line(command),
+                            after.0,
+                            after.1
+                        );
+                    }
+                    return Ok(flow);
+                }
+                Ok(Flow::Normal)
+            }
+            Frame::While {
+                condition,
+                body,
+                mut iterations,
+            } => {
+                let condition_value = self.evaluate_expression(&condition)?;
+                if self.value_to_bool(&condition_value)? {
+                    self.count_iteration(&mut iterations, "WHILE")?;
+                    let commands = Rc::clone(&body);
+                    frames.push(Frame::While {
+                        condition,
+                        body,
+                        iterations,
+                    });
+                    frames.push(Frame::Block { commands, next: 0 });
+                } else {
+                    self.loop_counters.pop();
+                }
+                Ok(Flow::Normal)
+            }
+            Frame::DoUntil {
+                body,
+                condition,
+                mut iterations,
+            } => {
+                let condition_value = self.evaluate_expression(&condition)?;
+                if !self.value_to_bool(&condition_value)? {
+                    self.count_iteration(&mut iterations, "DO...UNTIL")?;
+                    let commands = Rc::clone(&body);
+                    frames.push(Frame::DoUntil {
+                        body,
+                        condition,
+                        iterations,
+                    });
+                    frames.push(Frame::Block { commands, next: 0 });
+                } else {
+                    self.loop_counters.pop();
+                }
+                Ok(Flow::Normal)
+            }
+            Frame::For {
+                var,
+                mut current,
+                limit,
+                step_value,
+                step_amount,
+                body,
+                mut iterations,
+            } => {
+                // Step past the value the body last ran with
+                if iterations > 0 {
+                    self.stack.push(current);
+                    self.stack.push(step_value.clone());
+                    current = Operator::Add.apply(&mut self.stack)?;
+                }
+                let position = self.value_to_float(&current)?;
+                if (step_amount > 0.0 && position > limit)
+                    || (step_amount < 0.0 && position < limit)
+                {
+                    self.loop_counters.pop();
+                    return Ok(Flow::Normal);
+                }
+                self.count_iteration(&mut iterations, "FOR")?;
+                self.variables.set(&var, current.clone());
+
+                let commands = Rc::clone(&body);
+                frames.push(Frame::For {
+                    var,
+                    current,
+                    limit,
+                    step_value,
+                    step_amount,
+                    body,
+                    iterations,
+                });
+                frames.push(Frame::Block { commands, next: 0 });
+                Ok(Flow::Normal)
+            }
+            Frame::ForEach {
+                var,
+                items,
+                next,
+                body,
+                mut iterations,
+            } => {
+                if next >= items.len() {
+                    self.loop_counters.pop();
+                    return Ok(Flow::Normal);
+                }
+                self.count_iteration(&mut iterations, "FOREACH")?;
+                self.variables.set(&var, items[next].clone());
+
+                let commands = Rc::clone(&body);
+                frames.push(Frame::ForEach {
+                    var,
+                    items,
+                    next: next + 1,
+                    body,
+                    iterations,
+                });
+                frames.push(Frame::Block { commands, next: 0 });
+                Ok(Flow::Normal)
+            }
+            Frame::Call => {
+                self.leave_procedure();
+                Ok(Flow::Normal)
+            }
+        }
+    }
+
+    // One line per command: where it is, what it is, the values of its plain
+    // arguments and the turtle as the command finds it. Arguments that need
+    // working out (operators, functions, queries) are shown as written, since
+    // evaluating them here could have side effects such as RANDOM.
+    fn trace_command(&self, command: &Command) {
+        let args: Vec<String> = command
+            .expressions()
+            .into_iter()
+            .map(|expr| match expr {
+                Expression::Value(value) => self
+                    .resolve_value(value)
+                    .map_or_else(|_| "?".to_string(), |value| value.to_string()),
+                _ => format!("({})", expr),
+            })
+            .collect();
+        let state = self.turtle.snapshot();
+        eprintln!(
+            "[trace] line {} | {} | args=[{}] | x={} y={} heading={} pen={} color={}",
+            self.command_line(command),
+            first_line(command),
+            args.join(", "),
+            state.x,
+            state.y,
+            state.heading,
+            if state.pen_down { "down" } else { "up" },
+            self.turtle.get_color_name()
+        );
+    }
+
+    // Line number of a command, or - if it didn't come from this file
+    fn command_line(&self, command: &Command) -> String {
+        match command {
+            Command::Located(span, _) => line_and_column(&self.source, span.offset).0.to_string(),
+            _ => "-".to_string(),
+        }
+    }
+
+    // Run a single command. Commands with a body push it onto `frames`
+    // rather than running it here.
+    fn execute_command(
+        &mut self,
+        command: &Command,
+        frames: &mut Vec<Frame>,
+    ) -> Result<Flow, RSLogoError> {
         match command {
             Command::PenUp => {
                 self.turtle.pen_up();
-                Ok(())
+                Ok(Flow::Normal)
+            }
+            Command::PenReverse => {
+                self.turtle.toggle_pen_reverse();
+                Ok(Flow::Normal)
+            }
+            Command::BeginGroup(expr) => {
+                let value = self.evaluate_expression(expr)?;
+                let name = self.value_to_string(&value)?;
+                self.turtle.begin_group(name);
+                Ok(Flow::Normal)
+            }
+            Command::EndGroup => {
+                self.turtle.end_group()?;
+                Ok(Flow::Normal)
             }
             Command::PenDown => {
                 self.turtle.pen_down();
-                Ok(())
+                Ok(Flow::Normal)
             }
             Command::Forward(expr)
             | Command::Back(expr)
             | Command::Left(expr)
             | Command::Right(expr) => {
                 let value = self.evaluate_expression(expr)?;
-                let amount = self.value_to_int(&value)?;
+                let amount = self.value_to_float(&value)?;
                 match command {
-                    Command::Forward(_) => self.turtle.forward(amount),
-                    Command::Back(_) => self.turtle.back(amount),
-                    Command::Left(_) => self.turtle.left(amount),
-                    Command::Right(_) => self.turtle.right(amount),
+                    Command::Forward(_) => self.turtle.forward(amount)?,
+                    Command::Back(_) => self.turtle.back(amount)?,
+                    Command::Left(_) => self.turtle.left(amount)?,
+                    Command::Right(_) => self.turtle.right(amount)?,
                     _ => unreachable!(),
                 }
+                Ok(Flow::Normal)
             }
             Command::SetPenColor(expr) => {
                 let value = self.evaluate_expression(expr)?;
+                if let Value::String(name) = &value {
+                    if name.starts_with('#') {
+                        self.turtle.set_pen_color_hex(name)?;
+                        return Ok(Flow::Normal);
+                    }
+                    if name.parse::<f64>().is_err() {
+                        self.turtle.set_pen_color_name(name)?;
+                        return Ok(Flow::Normal);
+                    }
+                }
                 let color = self.value_to_int(&value)?;
-                if !(0..=15).contains(&color) {
+                self.turtle.set_pen_color(color)?;
+                Ok(Flow::Normal)
+            }
+            Command::SetRGB(red_expr, green_expr, blue_expr) => {
+                let mut channels = [0u8; 3];
+                for (channel, expr) in channels.iter_mut().zip([red_expr, green_expr, blue_expr]) {
+                    let value = self.evaluate_expression(expr)?;
+                    let amount = self.value_to_int(&value)?;
+                    *channel = u8::try_from(amount).map_err(|_| RSLogoError::InvalidArgument {
+                        command: "SETRGB".to_string(),
+                        argument: amount.to_string(),
+                        expected: "a channel value between 0 and 255".to_string(),
+                    })?;
+                }
+                self.turtle.set_rgb(channels[0], channels[1], channels[2]);
+                Ok(Flow::Normal)
+            }
+            Command::BeginFill => {
+                self.turtle.begin_fill();
+                Ok(Flow::Normal)
+            }
+            Command::EndFill => {
+                self.turtle.end_fill()?;
+                Ok(Flow::Normal)
+            }
+            Command::SetPenSize(expr) => {
+                let value = self.evaluate_expression(expr)?;
+                let size = self.value_to_int(&value)?;
+                if size < 1 {
                     return Err(RSLogoError::InvalidArgument {
-                        command: "SETPENCOLOR".to_string(),
-                        argument: color.to_string(),
-                        expected: "an integer between 0 and 15".to_string(),
+                        command: "SETPENSIZE".to_string(),
+                        argument: size.to_string(),
+                        expected: "a positive pen size".to_string(),
                     });
                 }
-                self.turtle.set_pen_color(color as u32)
+                self.turtle.set_pen_size(size as u32);
+                Ok(Flow::Normal)
             }
             Command::Turn(expr) | Command::SetHeading(expr) => {
                 let value = self.evaluate_expression(expr)?;
-                let degrees = self.value_to_int(&value)?;
+                // SETHEADING also takes a compass direction such as "NORTH
+                if let (Command::SetHeading(_), Value::String(name)) = (command, &value) {
+                    if name.parse::<f64>().is_err() {
+                        self.turtle.set_heading_name(name)?;
+                        return Ok(Flow::Normal);
+                    }
+                }
+                let degrees = self.value_to_float(&value)?;
+                if !degrees.is_finite() {
+                    let name = match command {
+                        Command::Turn(_) => "TURN",
+                        _ => "SETHEADING",
+                    };
+                    return Err(RSLogoError::InvalidArgument {
+                        command: name.to_string(),
+                        argument: value.to_string(),
+                        expected: "a finite number of degrees".to_string(),
+                    });
+                }
                 match command {
                     Command::Turn(_) => {
                         self.turtle.turn(degrees);
-                        Ok(())
+                        Ok(Flow::Normal)
                     }
                     Command::SetHeading(_) => {
                         self.turtle.set_heading(degrees);
-                        Ok(())
+                        Ok(Flow::Normal)
                     }
                     _ => unreachable!(),
                 }
             }
-            Command::SetX(expr) | Command::SetY(expr) => {
+            Command::SetX(expr)
+            | Command::SetY(expr)
+            | Command::JumpX(expr)
+            | Command::JumpY(expr) => {
                 let value = self.evaluate_expression(expr)?;
                 let location = self.value_to_int(&value)?;
                 match command {
-                    Command::SetX(_) => {
-                        self.turtle.set_x(location);
-                        Ok(())
-                    }
-                    Command::SetY(_) => {
-                        self.turtle.set_y(location);
-                        Ok(())
-                    }
+                    Command::SetX(_) => self.turtle.move_x(location)?,
+                    Command::SetY(_) => self.turtle.move_y(location)?,
+                    Command::JumpX(_) => self.turtle.jump_x(location),
+                    Command::JumpY(_) => self.turtle.jump_y(location),
                     _ => unreachable!(),
                 }
+                Ok(Flow::Normal)
+            }
+            Command::MoveBy(dx_expr, dy_expr) => {
+                let dx_value = self.evaluate_expression(dx_expr)?;
+                let dx = self.value_to_int(&dx_value)?;
+                let dy_value = self.evaluate_expression(dy_expr)?;
+                let dy = self.value_to_int(&dy_value)?;
+                self.turtle.move_by(dx, dy)?;
+                Ok(Flow::Normal)
+            }
+            Command::SetPos(expr) => {
+                let value = self.evaluate_expression(expr)?;
+                let (x, y) = match list_items(&value)? {
+                    [x, y] => (self.value_to_int(x)?, self.value_to_int(y)?),
+                    _ => {
+                        return Err(RSLogoError::InvalidArgument {
+                            command: "SETPOS".to_string(),
+                            argument: value.to_string(),
+                            expected: "a list of two coordinates".to_string(),
+                        })
+                    }
+                };
+                self.turtle.move_to(x, y)?;
+                Ok(Flow::Normal)
+            }
+            Command::Arc(angle_expr, radius_expr) => {
+                let angle_value = self.evaluate_expression(angle_expr)?;
+                let angle = self.value_to_float(&angle_value)?;
+                let radius_value = self.evaluate_expression(radius_expr)?;
+                let radius = self.value_to_float(&radius_value)?;
+                if radius < 0.0 {
+                    return Err(RSLogoError::InvalidArgument {
+                        command: "ARC".to_string(),
+                        argument: radius.to_string(),
+                        expected: "a non-negative radius".to_string(),
+                    });
+                }
+                self.turtle.arc(angle, radius)?;
+                Ok(Flow::Normal)
+            }
+            Command::Home => {
+                self.turtle.home();
+                Ok(Flow::Normal)
+            }
+            Command::ClearScreen => {
+                self.turtle.clear_screen()?;
+                Ok(Flow::Normal)
+            }
+            Command::PushTurtle => {
+                self.turtle.push_state();
+                Ok(Flow::Normal)
+            }
+            Command::PopTurtle => {
+                self.turtle.pop_state()?;
+                Ok(Flow::Normal)
+            }
+            Command::Label(expr) => {
+                let value = self.evaluate_expression(expr)?;
+                let text = self.value_to_string(&value)?;
+                self.turtle.label(text);
+                Ok(Flow::Normal)
+            }
+            Command::SetFontSize(expr) => {
+                let value = self.evaluate_expression(expr)?;
+                let size = self.value_to_int(&value)?;
+                if size <= 0 {
+                    return Err(RSLogoError::InvalidArgument {
+                        command: "SETFONTSIZE".to_string(),
+                        argument: size.to_string(),
+                        expected: "a positive font size".to_string(),
+                    });
+                }
+                self.turtle.set_font_size(size as u32);
+                Ok(Flow::Normal)
+            }
+            Command::Wait(expr) => {
+                let value = self.evaluate_expression(expr)?;
+                let centiseconds = self.value_to_int(&value)?;
+                let centiseconds =
+                    u32::try_from(centiseconds).map_err(|_| RSLogoError::InvalidArgument {
+                        command: "WAIT".to_string(),
+                        argument: centiseconds.to_string(),
+                        expected: "a non-negative number of hundredths of a second".to_string(),
+                    })?;
+                self.turtle.wait(centiseconds);
+                Ok(Flow::Normal)
+            }
+            Command::SetSpeed(expr) => {
+                let value = self.evaluate_expression(expr)?;
+                let steps = self.value_to_int(&value)?;
+                if steps < 1 {
+                    return Err(RSLogoError::InvalidArgument {
+                        command: "SETSPEED".to_string(),
+                        argument: steps.to_string(),
+                        expected: "at least 1 movement per frame".to_string(),
+                    });
+                }
+                self.turtle.set_steps_per_frame(steps as usize);
+                Ok(Flow::Normal)
+            }
+            Command::ShowTurtle | Command::HideTurtle => {
+                self.turtle
+                    .set_show_turtle(matches!(command, Command::ShowTurtle));
+                Ok(Flow::Normal)
             }
             Command::Make(name_expr, value_expr) => {
                 let name = self.evaluate_expression(name_expr)?;
@@ -119,6 +789,8 @@ impl Interpreter {
                     Value::String(s) => {
                         if let Ok(n) = s.parse::<i32>() {
                             Value::Number(n)
+                        } else if let Ok(x) = s.parse::<f64>() {
+                            Value::Float(x)
                         } else {
                             Value::String(s)
                         }
@@ -126,12 +798,11 @@ impl Interpreter {
                     _ => value,
                 };
 
-                self.variables.set(&name_str, stored_value);
-                Ok(())
+                self.variables.make(&name_str, stored_value);
+                Ok(Flow::Normal)
             }
             Command::AddAssign(name, expr) => {
-                let value = self.evaluate_expression(expr)?;
-                let amount = self.value_to_int(&value)?;
+                let amount = self.evaluate_expression(expr)?;
 
                 // Resolve the variable name stored in 'name'
                 let var_name = if let Some(stripped) = name.strip_prefix(':') {
@@ -152,87 +823,329 @@ impl Interpreter {
                     }
                 })?;
 
-                let current_amount = self.value_to_int(current_value)?;
-                let new_value = current_amount + amount;
-                self.variables.set(&var_name, Value::Number(new_value));
-                Ok(())
+                // Reuse the + operator so ints and floats promote the same way
+                self.stack.push(current_value.clone());
+                self.stack.push(amount);
+                let new_value = Operator::Add.apply(&mut self.stack)?;
+                self.variables.set(&var_name, new_value);
+                Ok(Flow::Normal)
             }
             Command::If(condition, body) => {
                 let condition_value = self.evaluate_expression(condition)?;
                 if self.value_to_bool(&condition_value)? {
-                    for cmd in body {
-                        self.execute_command(cmd)?;
-                    }
+                    frames.push(Frame::Block {
+                        commands: Rc::clone(body),
+                        next: 0,
+                    });
                 }
-                Ok(())
+                Ok(Flow::Normal)
+            }
+            Command::IfElse(condition, then_body, else_body) => {
+                let condition_value = self.evaluate_expression(condition)?;
+                let body = if self.value_to_bool(&condition_value)? {
+                    then_body
+                } else {
+                    else_body
+                };
+                frames.push(Frame::Block {
+                    commands: Rc::clone(body),
+                    next: 0,
+                });
+                Ok(Flow::Normal)
             }
             Command::While(condition, body) => {
-                loop {
-                    let condition_value = self.evaluate_expression(condition)?;
-                    if !self.value_to_bool(&condition_value)? {
-                        break;
-                    }
-                    for cmd in body {
-                        self.execute_command(cmd)?;
-                    }
+                self.loop_counters.push(0);
+                frames.push(Frame::While {
+                    condition: condition.clone(),
+                    body: Rc::clone(body),
+                    iterations: 0,
+                });
+                Ok(Flow::Normal)
+            }
+            Command::DoUntil(body, condition) => {
+                // The body always runs once before the condition is checked
+                let mut iterations = 0;
+                self.loop_counters.push(0);
+                self.count_iteration(&mut iterations, "DO...UNTIL")?;
+                frames.push(Frame::DoUntil {
+                    body: Rc::clone(body),
+                    condition: condition.clone(),
+                    iterations,
+                });
+                frames.push(Frame::Block {
+                    commands: Rc::clone(body),
+                    next: 0,
+                });
+                Ok(Flow::Normal)
+            }
+            Command::For {
+                var,
+                from,
+                to,
+                step,
+                body,
+            } => {
+                let current = self.evaluate_expression(from)?;
+                let to_value = self.evaluate_expression(to)?;
+                let limit = self.value_to_float(&to_value)?;
+                let step_value = self.evaluate_expression(step)?;
+                let step_amount = self.value_to_float(&step_value)?;
+                if step_amount == 0.0 {
+                    return Err(RSLogoError::InvalidArgument {
+                        command: "FOR".to_string(),
+                        argument: step_value.to_string(),
+                        expected: "a non-zero step".to_string(),
+                    });
                 }
-                Ok(())
+
+                // The loop variable lives in the scope the FOR runs in
+                self.variables.declare_local(var);
+                self.loop_counters.push(0);
+                frames.push(Frame::For {
+                    var: var.clone(),
+                    current,
+                    limit,
+                    step_value,
+                    step_amount,
+                    body: Rc::clone(body),
+                    iterations: 0,
+                });
+                Ok(Flow::Normal)
+            }
+            Command::ForEach { var, list, body } => {
+                let items = match self.evaluate_expression(list)? {
+                    Value::List(items) => items,
+                    other => {
+                        return Err(RSLogoError::InvalidArgument {
+                            command: "FOREACH".to_string(),
+                            argument: other.to_string(),
+                            expected: "a list".to_string(),
+                        })
+                    }
+                };
+
+                // Like FOR, the item variable lives in the scope the loop runs in.
+                // An empty list never runs the body.
+                self.variables.declare_local(var);
+                self.loop_counters.push(0);
+                frames.push(Frame::ForEach {
+                    var: var.clone(),
+                    items,
+                    next: 0,
+                    body: Rc::clone(body),
+                    iterations: 0,
+                });
+                Ok(Flow::Normal)
             }
             Command::Expression(expr) => {
                 self.evaluate_expression(expr)?;
-                Ok(())
+                Ok(Flow::Normal)
             }
             Command::ProcedureDefinition {
                 name,
                 parameters,
+                rest,
                 body,
             } => {
                 // Just store the procedure definition without evaluating variables
                 self.procedures.define_procedure(
                     name.clone(),
                     parameters.clone(),
-                    body.clone(),
+                    rest.clone(),
+                    Rc::clone(body),
                     &mut self.variables,
-                )
+                )?;
+                Ok(Flow::Normal)
+            }
+            Command::Erase(name) => {
+                self.procedures.erase_procedure(name)?;
+                Ok(Flow::Normal)
             }
             Command::ProcedureCall { name, arguments } => {
-                // Get procedure first
-                let (parameters, body) = {
-                    let procedure = self.procedures.get_procedure(name).ok_or_else(|| {
-                        RSLogoError::InvalidArgument {
-                            command: "procedure call".to_string(),
-                            argument: name.clone(),
-                            expected: "a defined procedure name".to_string(),
-                        }
-                    })?;
-                    (
-                        procedure.get_parameters().clone(),
-                        procedure.get_body().clone(),
-                    )
-                };
-
-                // Evaluate arguments
-                let mut evaluated_args = Vec::new();
-                for arg in arguments {
-                    let value = self.evaluate_expression(arg)?;
-                    evaluated_args.push(value);
+                let body = self.enter_procedure(name, arguments)?;
+                frames.push(Frame::Call);
+                frames.push(Frame::Block {
+                    commands: body,
+                    next: 0,
+                });
+                Ok(Flow::Normal)
+            }
+            Command::Output(expr) => {
+                if !self.procedures.in_procedure() {
+                    return Err(RSLogoError::MisplacedCommand {
+                        command: "OUTPUT".to_string(),
+                        context: "inside a procedure".to_string(),
+                    });
+                }
+                let value = self.evaluate_expression(expr)?;
+                Ok(Flow::Output(value))
+            }
+            Command::Stop => {
+                if !self.procedures.in_procedure() {
+                    return Err(RSLogoError::MisplacedCommand {
+                        command: "STOP".to_string(),
+                        context: "inside a procedure".to_string(),
+                    });
+                }
+                Ok(Flow::Stop)
+            }
+            Command::Reset => {
+                // Running loops and procedure calls would be left with
+                // nothing to come back to. A CALL in an expression runs its
+                // body in a fresh set of frames, so the procedure stack and
+                // loop counters are checked too.
+                if self.procedures.in_procedure()
+                    || !self.loop_counters.is_empty()
+                    || !frames
+                        .iter()
+                        .all(|frame| matches!(frame, Frame::Block { .. }))
+                {
+                    return Err(RSLogoError::MisplacedCommand {
+                        command: command.to_string(),
+                        context: "outside procedures and loops".to_string(),
+                    });
+                }
+                self.reset()?;
+                Ok(Flow::Normal)
+            }
+            Command::Continue | Command::Break => {
+                if !in_loop(frames) {
+                    return Err(RSLogoError::MisplacedCommand {
+                        command: command.to_string(),
+                        context: "inside a WHILE, DO...UNTIL, FOR or FOREACH loop".to_string(),
+                    });
                 }
+                match command {
+                    Command::Continue => Ok(Flow::Continue),
+                    _ => Ok(Flow::Break),
+                }
+            }
+            Command::Local(name) => {
+                self.variables.declare_local(name);
+                Ok(Flow::Normal)
+            }
+            Command::Global(name) => {
+                self.variables.declare_global(name);
+                Ok(Flow::Normal)
+            }
+            Command::Print(expr) => {
+                let value = self.evaluate_expression(expr)?;
+                println!("{}", self.value_to_string(&value)?);
+                Ok(Flow::Normal)
+            }
+            Command::Assert(condition) => {
+                let condition_value = self.evaluate_expression(condition)?;
+                if self.value_to_bool(&condition_value)? {
+                    Ok(Flow::Normal)
+                } else {
+                    Err(RSLogoError::AssertionFailed(condition.to_string()))
+                }
+            }
+            Command::Include(_) => Err(RSLogoError::MisplacedCommand {
+                command: "INCLUDE".to_string(),
+                context: "at the top level of a program read with includes enabled".to_string(),
+            }),
+            // Errors are pointed at their command by locate_error
+            Command::Located(_, inner) => self.execute_command(inner, frames),
+        }
+    }
 
-                // Create parameter scope
-                self.procedures
-                    .push_parameters(&parameters, evaluated_args)?;
+    fn check_deadline(&mut self) -> Result<(), RSLogoError> {
+        self.steps += 1;
+        if !self.steps.is_multiple_of(STEPS_PER_TIME_CHECK) {
+            return Ok(());
+        }
+        match (self.deadline, self.timeout) {
+            (Some(deadline), Some(timeout)) if Instant::now() >= deadline => {
+                Err(RSLogoError::Timeout(timeout))
+            }
+            _ => Ok(()),
+        }
+    }
 
-                // Execute body - variable resolution happens here
-                for cmd in &body {
-                    self.execute_command(cmd)?;
-                }
+    fn count_iteration(
+        &mut self,
+        iterations: &mut usize,
+        command: &str,
+    ) -> Result<(), RSLogoError> {
+        *iterations += 1;
+        if let Some(counter) = self.loop_counters.last_mut() {
+            *counter = *iterations;
+        }
+        if *iterations > self.max_iterations {
+            return Err(RSLogoError::IterationLimitExceeded {
+                command: command.to_string(),
+                limit: self.max_iterations,
+            });
+        }
+        Ok(())
+    }
 
-                self.procedures.pop_parameters();
-                Ok(())
+    // Call a procedure from inside an expression and return the value it
+    // OUTPUTs, if any. The body gets a run of its own so the value can be
+    // handed straight back to the expression waiting on it.
+    fn call_procedure(
+        &mut self,
+        name: &str,
+        arguments: &[Expression],
+    ) -> Result<Option<Value>, RSLogoError> {
+        let body = self.enter_procedure(name, arguments)?;
+        let flow = self.run(vec![Frame::Block {
+            commands: body,
+            next: 0,
+        }])?;
+        self.leave_procedure();
+        match flow {
+            Flow::Output(value) => Ok(Some(value)),
+            Flow::Normal | Flow::Stop => Ok(None),
+            // A body run on its own has no loop around it to leave
+            Flow::Continue | Flow::Break => {
+                unreachable!("CONTINUE and BREAK outside a loop are rejected")
             }
         }
     }
 
+    // Bind a procedure's arguments in a fresh scope and hand back its body
+    fn enter_procedure(
+        &mut self,
+        name: &str,
+        arguments: &[Expression],
+    ) -> Result<Block, RSLogoError> {
+        // Get procedure first
+        let (parameters, rest, body) = {
+            let procedure = self.procedures.get_procedure(name).ok_or_else(|| {
+                RSLogoError::InvalidArgument {
+                    command: "procedure call".to_string(),
+                    argument: name.to_string(),
+                    expected: "a defined procedure name".to_string(),
+                }
+            })?;
+            (
+                procedure.get_parameters(),
+                procedure.get_rest(),
+                procedure.get_body(),
+            )
+        };
+
+        // Evaluate arguments
+        let mut evaluated_args = Vec::new();
+        for arg in arguments {
+            let value = self.evaluate_expression(arg)?;
+            evaluated_args.push(value);
+        }
+
+        // Create parameter scope
+        self.procedures
+            .push_parameters(name, &parameters, rest.as_deref(), evaluated_args)?;
+        self.variables.push_scope();
+        Ok(body)
+    }
+
+    fn leave_procedure(&mut self) {
+        self.variables.pop_scope();
+        self.procedures.pop_parameters();
+    }
+
     fn evaluate_expression(&mut self, expr: &Expression) -> Result<Value, RSLogoError> {
         match expr {
             Expression::Value(v) => {
@@ -240,6 +1153,17 @@ impl Interpreter {
                 self.stack.push(resolved.clone());
                 Ok(resolved)
             }
+            // The right side of AND and OR is only evaluated when it can
+            // still change the answer
+            Expression::BinaryOp(op @ (Operator::And | Operator::Or), left, right) => {
+                let left_value = self.evaluate_expression(left)?;
+                let left_bool = self.value_to_bool(&left_value)?;
+                if left_bool == (*op == Operator::Or) {
+                    return Ok(Value::Boolean(left_bool));
+                }
+                let right_value = self.evaluate_expression(right)?;
+                Ok(Value::Boolean(self.value_to_bool(&right_value)?))
+            }
             Expression::BinaryOp(op, left, right) => {
                 let left_value = self.evaluate_expression(left)?;
                 let right_value = self.evaluate_expression(right)?;
@@ -249,20 +1173,213 @@ impl Interpreter {
                 Ok(result)
             }
             Expression::Query(query) => {
-                let result = self.resolve_query(query)?;
+                let result = match query.as_str() {
+                    // The one query that changes the turtle, so it can't go
+                    // through resolve_query
+                    "NEXTCOLOR" => self.next_color()?,
+                    _ => self.resolve_query(query)?,
+                };
+                self.stack.push(result.clone());
+                Ok(result)
+            }
+            Expression::Function(name, arguments) => {
+                let mut values = Vec::new();
+                for arg in arguments {
+                    values.push(self.evaluate_expression(arg)?);
+                }
+                let result = self.apply_function(name, &values)?;
+                self.stack.push(result.clone());
+                Ok(result)
+            }
+            Expression::Call(name, arguments) => {
+                // A procedure that finishes without OUTPUT has no value to offer
+                let result = self.call_procedure(name, arguments)?.ok_or_else(|| {
+                    RSLogoError::UnexpectedValue {
+                        expected: format!("procedure '{}' to OUTPUT a value", name),
+                        got: "no output".to_string(),
+                    }
+                })?;
                 self.stack.push(result.clone());
                 Ok(result)
             }
         }
     }
 
+    fn apply_function(&mut self, name: &str, args: &[Value]) -> Result<Value, RSLogoError> {
+        let arg = match (name, args) {
+            ("RANDOM", [bound]) => return self.random_below(bound),
+            ("PICK", [list]) => return self.pick(list),
+            ("SUM", [list]) => return self.reduce(list, Operator::Add, 0),
+            ("PRODUCT", [list]) => return self.reduce(list, Operator::Multiply, 1),
+            ("INT" | "ROUND" | "ABS", [arg]) => return self.to_whole_number(name, arg),
+            ("MINUS", [arg]) => {
+                // Same arithmetic as - "0 x, so overflow and type errors match
+                self.stack.push(Value::Number(0));
+                self.stack.push(arg.clone());
+                return Operator::Subtract.apply(&mut self.stack);
+            }
+            ("TOWARDS", [x, y]) => return self.towards(x, y),
+            ("DEFINEDP", [name]) => {
+                let name = self.value_to_string(name)?;
+                return Ok(Value::Boolean(
+                    self.procedures.get_procedure(&name).is_some(),
+                ));
+            }
+            ("VARDEFINEDP", [name]) => {
+                // Bound as a parameter of a running procedure or by MAKE
+                let name = self.value_to_string(name)?;
+                return Ok(Value::Boolean(
+                    self.procedures.get_parameter_value(&name).is_some()
+                        || self.variables.get(&name).is_some(),
+                ));
+            }
+            (_, [arg]) => arg,
+            _ => {
+                return Err(RSLogoError::InvalidArgument {
+                    command: name.to_string(),
+                    argument: format!("{} arguments", args.len()),
+                    expected: "a single argument".to_string(),
+                })
+            }
+        };
+
+        let x = self.value_to_float(arg)?;
+        let result = match name {
+            "SQRT" => {
+                if x < 0.0 {
+                    return Err(RSLogoError::InvalidArgument {
+                        command: name.to_string(),
+                        argument: x.to_string(),
+                        expected: "a non-negative number".to_string(),
+                    });
+                }
+                x.sqrt()
+            }
+            // Angles are in degrees to match TURN and SETHEADING
+            "SIN" => x.to_radians().sin(),
+            "COS" => x.to_radians().cos(),
+            "TAN" => x.to_radians().tan(),
+            _ => {
+                return Err(RSLogoError::InvalidArgument {
+                    command: "function".to_string(),
+                    argument: name.to_string(),
+                    expected: "a known function".to_string(),
+                })
+            }
+        };
+        if result.is_finite() {
+            Ok(Value::Float(result))
+        } else {
+            Err(RSLogoError::Overflow)
+        }
+    }
+
+    // Step the pen to the next palette color, wrapping after the last, and
+    // report its index. A custom RGB pen steps to the first palette color.
+    fn next_color(&mut self) -> Result<Value, RSLogoError> {
+        let next = match self.turtle.get_pen_color() {
+            Some(index) => (index + 1) % self.turtle.palette_len() as u32,
+            None => 0,
+        };
+        self.turtle.set_pen_color(next as i32)?;
+        Ok(Value::Number(next as i32))
+    }
+
+    // Heading from the turtle to (x, y) in the SETHEADING convention:
+    // 0 is up the canvas and angles grow clockwise
+    fn towards(&self, x: &Value, y: &Value) -> Result<Value, RSLogoError> {
+        let x = self.value_to_float(x)?;
+        let y = self.value_to_float(y)?;
+        Ok(Value::Float(self.turtle.heading_towards(x, y)))
+    }
+
+    // INT truncates toward zero, ROUND rounds half away from zero and ABS
+    // drops the sign and then truncates, so each gives a whole Number.
+    // Integers are handled exactly rather than going through f64.
+    fn to_whole_number(&self, name: &str, arg: &Value) -> Result<Value, RSLogoError> {
+        if let Ok(Numeric::Int(n)) = rs_operators::value_to_number(arg) {
+            return match name {
+                "ABS" => n
+                    .checked_abs()
+                    .map(Value::Number)
+                    .ok_or(RSLogoError::Overflow),
+                _ => Ok(Value::Number(n)),
+            };
+        }
+        let x = self.value_to_float(arg)?;
+        let result = match name {
+            "INT" => x.trunc(),
+            "ROUND" => x.round(),
+            _ => x.abs().trunc(),
+        };
+        if result.is_finite() && (i32::MIN as f64..=i32::MAX as f64).contains(&result) {
+            Ok(Value::Number(result as i32))
+        } else {
+            Err(RSLogoError::Overflow)
+        }
+    }
+
+    // One element of a list, chosen with the same seeded generator as RANDOM
+    fn pick(&mut self, list: &Value) -> Result<Value, RSLogoError> {
+        let items = list_items(list)?;
+        if items.is_empty() {
+            return Err(RSLogoError::InvalidArgument {
+                command: "PICK".to_string(),
+                argument: list.to_string(),
+                expected: "a non-empty list".to_string(),
+            });
+        }
+        let index = self.random.below(items.len() as u32) as usize;
+        Ok(items[index].clone())
+    }
+
+    // Fold a list with the same arithmetic as the operator, so items are
+    // coerced, and overflow is caught, exactly as + and * do it. An empty
+    // list gives the starting value.
+    fn reduce(
+        &mut self,
+        list: &Value,
+        operator: Operator,
+        start: i32,
+    ) -> Result<Value, RSLogoError> {
+        let mut total = Value::Number(start);
+        for item in list_items(list)? {
+            self.stack.push(total);
+            self.stack.push(item.clone());
+            total = operator.apply(&mut self.stack)?;
+        }
+        Ok(total)
+    }
+
+    // RANDOM n picks from [0, n): whole numbers for an integer bound, floats otherwise
+    fn random_below(&mut self, bound: &Value) -> Result<Value, RSLogoError> {
+        let bound_value = self.value_to_float(bound)?;
+        if bound_value <= 0.0 {
+            return Err(RSLogoError::InvalidArgument {
+                command: "RANDOM".to_string(),
+                argument: bound_value.to_string(),
+                expected: "a positive bound".to_string(),
+            });
+        }
+        if bound_value.fract() == 0.0 && bound_value <= i32::MAX as f64 {
+            Ok(Value::Number(self.random.below(bound_value as u32) as i32))
+        } else {
+            Ok(Value::Float(self.random.unit() * bound_value))
+        }
+    }
+
     fn value_to_int(&self, value: &Value) -> Result<i32, RSLogoError> {
         match value {
             Value::Number(n) => Ok(*n),
-            Value::String(s) => s.parse::<i32>().map_err(|_| RSLogoError::UnexpectedValue {
-                expected: "a number".to_string(),
-                got: s.clone(),
-            }),
+            Value::Float(x) => Ok(x.round() as i32),
+            Value::String(s) => match rs_operators::value_to_number(value) {
+                Ok(Numeric::Int(n)) => Ok(n),
+                Ok(Numeric::Float(x)) => Ok(x.round() as i32),
+                Err(_) => Err(RSLogoError::UnexpectedValue {
+                    expected: "a number".to_string(),
+                    got: s.clone(),
+                }),
+            },
             Value::Variable(var_name) => {
                 let var_value =
                     self.variables
@@ -274,24 +1391,39 @@ impl Interpreter {
                 self.value_to_int(var_value)
             }
             Value::Boolean(b) => Ok(if *b { 1 } else { 0 }),
+            Value::List(_) => Err(RSLogoError::UnexpectedValue {
+                expected: "a number".to_string(),
+                got: value.to_string(),
+            }),
         }
     }
 
-    fn value_to_bool(&self, value: &Value) -> Result<bool, RSLogoError> {
+    fn value_to_float(&self, value: &Value) -> Result<f64, RSLogoError> {
         match value {
-            Value::Boolean(b) => Ok(*b),
-            Value::String(s) => Ok(s.to_uppercase() == "TRUE"),
-            Value::Number(n) => Ok(*n != 0),
-            _ => Err(RSLogoError::TypeMismatch),
+            Value::Float(x) => Ok(*x),
+            Value::String(s) => rs_operators::value_to_number(value)
+                .map(Numeric::as_f64)
+                .map_err(|_| RSLogoError::UnexpectedValue {
+                    expected: "a number".to_string(),
+                    got: s.clone(),
+                }),
+            _ => self.value_to_int(value).map(|n| n as f64),
         }
     }
 
+    // Conditions follow the same rules as the operators
+    fn value_to_bool(&self, value: &Value) -> Result<bool, RSLogoError> {
+        rs_operators::value_to_bool(value)
+    }
+
     fn value_to_string(&self, value: &Value) -> Result<String, RSLogoError> {
         match value {
             Value::String(s) => Ok(s.clone()),
             Value::Number(n) => Ok(n.to_string()),
+            Value::Float(x) => Ok(x.to_string()),
             Value::Variable(var) => self.resolve_variable_value(var),
             Value::Boolean(b) => Ok(b.to_string()),
+            Value::List(_) => Ok(value.to_string()),
         }
     }
 
@@ -299,6 +1431,7 @@ impl Interpreter {
         match self.variables.get(name) {
             Some(Value::String(s)) => Ok(s.clone()),
             Some(Value::Number(n)) => Ok(n.to_string()),
+            Some(Value::Float(x)) => Ok(x.to_string()),
             Some(_) => Err(RSLogoError::UnexpectedValue {
                 expected: "a string or number".to_string(),
                 got: format!("{:?}", self.variables.get(name)),
@@ -309,6 +1442,7 @@ impl Interpreter {
                     match param_value {
                         Value::String(s) => return Ok(s.clone()),
                         Value::Number(n) => return Ok(n.to_string()),
+                        Value::Float(x) => return Ok(x.to_string()),
                         _ => {}
                     }
                 }
@@ -354,7 +1488,15 @@ impl Interpreter {
             }
             Value::String(s) if s.to_uppercase() == "TRUE" => Ok(Value::Boolean(true)),
             Value::String(s) if s.to_uppercase() == "FALSE" => Ok(Value::Boolean(false)),
-            Value::Number(_) | Value::String(_) | Value::Boolean(_) => Ok(value.clone()),
+            Value::Number(_) | Value::Float(_) | Value::String(_) | Value::Boolean(_) => {
+                Ok(value.clone())
+            }
+            // List elements may name variables, which are looked up now
+            Value::List(items) => items
+                .iter()
+                .map(|item| self.resolve_value(item))
+                .collect::<Result<Vec<_>, _>>()
+                .map(Value::List),
         }
     }
 
@@ -362,13 +1504,94 @@ impl Interpreter {
         match query {
             "XCOR" => Ok(Value::Number(self.turtle.get_x())),
             "YCOR" => Ok(Value::Number(self.turtle.get_y())),
-            "HEADING" => Ok(Value::Number(self.turtle.get_heading())),
-            "COLOR" => Ok(Value::Number(self.turtle.get_pen_color() as i32)),
+            // [XCOR YCOR], ready to hand back to SETPOS
+            "POS" => Ok(Value::List(vec![
+                Value::Number(self.turtle.get_x()),
+                Value::Number(self.turtle.get_y()),
+            ])),
+            // Whole headings read as integers; only a fractional turn gives
+            // a float
+            "HEADING" => {
+                let heading = self.turtle.get_heading();
+                if heading.fract() == 0.0 {
+                    Ok(Value::Number(heading as i32))
+                } else {
+                    Ok(Value::Float(heading))
+                }
+            }
+            // A custom RGB pen has no palette index, which COLOR reports as -1
+            "COLOR" => Ok(Value::Number(
+                self.turtle.get_pen_color().map_or(-1, |c| c as i32),
+            )),
+            "COLORNAME" => Ok(Value::String(self.turtle.get_color_name())),
+            "PENSIZE" => Ok(Value::Number(self.turtle.get_pen_size() as i32)),
+            "PENDOWNP" => Ok(Value::Boolean(self.turtle.is_pen_down())),
+            // Channels of the actual pen color, palette or custom RGB alike
+            "PENRED" => Ok(Value::Number(self.turtle.get_color().red as i32)),
+            "PENGREEN" => Ok(Value::Number(self.turtle.get_color().green as i32)),
+            "PENBLUE" => Ok(Value::Number(self.turtle.get_color().blue as i32)),
+            "WIDTH" => Ok(Value::Number(self.turtle.get_width() as i32)),
+            "HEIGHT" => Ok(Value::Number(self.turtle.get_height() as i32)),
+            // 1-based index of the innermost loop's current iteration
+            "REPCOUNT" => match self.loop_counters.last() {
+                Some(&count) => Ok(Value::Number(count as i32)),
+                None => Err(RSLogoError::MisplacedCommand {
+                    command: "REPCOUNT".to_string(),
+                    context: "inside a WHILE, DO...UNTIL, FOR or FOREACH loop".to_string(),
+                }),
+            },
             _ => Err(RSLogoError::InvalidArgument {
                 command: "query".to_string(),
                 argument: query.to_string(),
-                expected: "XCOR, YCOR, HEADING, or COLOR".to_string(),
+                expected: "a known query".to_string(),
             }),
         }
     }
 }
+
+// Whether the running code is inside a loop, not counting loops around
+// the procedure call it is in
+fn in_loop(frames: &[Frame]) -> bool {
+    for frame in frames.iter().rev() {
+        match frame {
+            Frame::Block { .. } => {}
+            Frame::Call => return false,
+            _ => return true,
+        }
+    }
+    false
+}
+
+// A command as written, cut short after its first line so a procedure
+// definition doesn't print its whole body
+fn first_line(command: &Command) -> String {
+    let text = command.unlocated().to_string();
+    text.lines().next().unwrap_or("").trim().to_string()
+}
+
+fn list_items(list: &Value) -> Result<&[Value], RSLogoError> {
+    match list {
+        Value::List(items) => Ok(items),
+        _ => Err(RSLogoError::UnexpectedValue {
+            expected: "a list".to_string(),
+            got: list.to_string(),
+        }),
+    }
+}
+
+// Point an error at the innermost command it happened in. Each block frame's
+// previous command is the one still running; if it wasn't written in this
+// file (e.g. it came from an INCLUDE), fall back to whatever called it.
+fn locate_error(frames: &[Frame], err: RSLogoError) -> RSLogoError {
+    let span = frames.iter().rev().find_map(|frame| match frame {
+        Frame::Block { commands, next } if *next > 0 => match &commands[next - 1] {
+            Command::Located(span, _) => Some(*span),
+            _ => None,
+        },
+        _ => None,
+    });
+    match span {
+        Some(span) => err.located(span.offset, span.len),
+        None => err,
+    }
+}
@@ -0,0 +1,149 @@
+use image::{Rgba, RgbaImage};
+use unsvg::Color;
+
+// unsvg only draws lines, so text is kept aside and added to each output on
+// the way out: as <text> in an SVG, and drawn with a small bitmap font in
+// raster images. Labels are always horizontal.
+pub struct Label {
+    // Canvas pixel of the start of the baseline, before --scale
+    pub x: i32,
+    pub y: i32,
+    pub text: String,
+    pub color: Color,
+    // Height of a line of text in pixels, before --scale
+    pub font_size: u32,
+}
+
+pub const DEFAULT_FONT_SIZE: u32 = 12;
+
+// Glyphs are 3x5 font pixels with one pixel of space after, and a line
+// is one pixel taller than a glyph
+const GLYPH_WIDTH: u32 = 3;
+const GLYPH_HEIGHT: u32 = 5;
+const ADVANCE: u32 = GLYPH_WIDTH + 1;
+const LINE_HEIGHT: u32 = GLYPH_HEIGHT + 1;
+
+// Add a <text> element per label just before the closing tag
+pub fn add_to_svg(svg: &str, labels: &[Label]) -> String {
+    let end = match svg.rfind("</svg>") {
+        Some(end) => end,
+        None => return svg.to_string(),
+    };
+    let mut text = String::new();
+    for label in labels {
+        text.push_str(&format!(
+            "<text x=\"{}\" y=\"{}\" fill=\"rgb({},{},{})\" font-family=\"monospace\" font-size=\"{}\">{}</text>\n",
+            label.x,
+            label.y,
+            label.color.red,
+            label.color.green,
+            label.color.blue,
+            label.font_size,
+            escape_xml(&label.text)
+        ));
+    }
+    format!("{}{}{}", &svg[..end], text, &svg[end..])
+}
+
+// Draw every label into a rasterized image, clipping at its edges
+pub fn draw_on_pixels(pixels: &mut RgbaImage, labels: &[Label], scale: u32) {
+    for label in labels {
+        // The bitmap font only comes in whole pixels, so sizes round down
+        let size = ((label.font_size / LINE_HEIGHT).max(1) * scale) as i64;
+        let color = Rgba([label.color.red, label.color.green, label.color.blue, 255]);
+        let left = label.x as i64 * scale as i64;
+        let top = label.y as i64 * scale as i64 - GLYPH_HEIGHT as i64 * size;
+        for (i, c) in label.text.chars().enumerate() {
+            let glyph_left = left + (i as u32 * ADVANCE) as i64 * size;
+            for (row, bits) in glyph(c).iter().enumerate() {
+                for column in 0..GLYPH_WIDTH {
+                    if bits & (1 << (GLYPH_WIDTH - 1 - column)) != 0 {
+                        let x = glyph_left + column as i64 * size;
+                        let y = top + row as i64 * size;
+                        fill_square(pixels, x, y, size, color);
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn fill_square(pixels: &mut RgbaImage, x: i64, y: i64, size: i64, color: Rgba<u8>) {
+    for py in y.max(0)..(y + size).min(pixels.height() as i64) {
+        for px in x.max(0)..(x + size).min(pixels.width() as i64) {
+            pixels.put_pixel(px as u32, py as u32, color);
+        }
+    }
+}
+
+pub fn escape_xml(text: &str) -> String {
+    let mut escaped = String::new();
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// Rows of a glyph from the top, three bits each with the leftmost pixel
+// highest. Lowercase letters share the uppercase shapes; anything the font
+// doesn't have is drawn as a question mark.
+fn glyph(c: char) -> [u8; 5] {
+    match c.to_ascii_uppercase() {
+        ' ' => [0b000, 0b000, 0b000, 0b000, 0b000],
+        'A' => [0b010, 0b101, 0b111, 0b101, 0b101],
+        'B' => [0b110, 0b101, 0b110, 0b101, 0b110],
+        'C' => [0b011, 0b100, 0b100, 0b100, 0b011],
+        'D' => [0b110, 0b101, 0b101, 0b101, 0b110],
+        'E' => [0b111, 0b100, 0b110, 0b100, 0b111],
+        'F' => [0b111, 0b100, 0b110, 0b100, 0b100],
+        'G' => [0b011, 0b100, 0b101, 0b101, 0b011],
+        'H' => [0b101, 0b101, 0b111, 0b101, 0b101],
+        'I' => [0b111, 0b010, 0b010, 0b010, 0b111],
+        'J' => [0b001, 0b001, 0b001, 0b101, 0b010],
+        'K' => [0b101, 0b101, 0b110, 0b101, 0b101],
+        'L' => [0b100, 0b100, 0b100, 0b100, 0b111],
+        'M' => [0b101, 0b111, 0b111, 0b101, 0b101],
+        'N' => [0b110, 0b101, 0b101, 0b101, 0b101],
+        'O' => [0b010, 0b101, 0b101, 0b101, 0b010],
+        'P' => [0b110, 0b101, 0b110, 0b100, 0b100],
+        'Q' => [0b010, 0b101, 0b101, 0b110, 0b011],
+        'R' => [0b110, 0b101, 0b110, 0b101, 0b101],
+        'S' => [0b011, 0b100, 0b010, 0b001, 0b110],
+        'T' => [0b111, 0b010, 0b010, 0b010, 0b010],
+        'U' => [0b101, 0b101, 0b101, 0b101, 0b111],
+        'V' => [0b101, 0b101, 0b101, 0b101, 0b010],
+        'W' => [0b101, 0b101, 0b111, 0b111, 0b101],
+        'X' => [0b101, 0b101, 0b010, 0b101, 0b101],
+        'Y' => [0b101, 0b101, 0b010, 0b010, 0b010],
+        'Z' => [0b111, 0b001, 0b010, 0b100, 0b111],
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b110, 0b001, 0b010, 0b100, 0b111],
+        '3' => [0b110, 0b001, 0b010, 0b001, 0b110],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b110, 0b001, 0b110],
+        '6' => [0b011, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b010, 0b010, 0b010],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b110],
+        '.' => [0b000, 0b000, 0b000, 0b000, 0b010],
+        ',' => [0b000, 0b000, 0b000, 0b010, 0b100],
+        '-' => [0b000, 0b000, 0b111, 0b000, 0b000],
+        '+' => [0b000, 0b010, 0b111, 0b010, 0b000],
+        '=' => [0b000, 0b111, 0b000, 0b111, 0b000],
+        '_' => [0b000, 0b000, 0b000, 0b000, 0b111],
+        ':' => [0b000, 0b010, 0b000, 0b010, 0b000],
+        '!' => [0b010, 0b010, 0b010, 0b000, 0b010],
+        '\'' => [0b010, 0b010, 0b000, 0b000, 0b000],
+        '/' => [0b001, 0b001, 0b010, 0b100, 0b100],
+        '(' => [0b001, 0b010, 0b010, 0b010, 0b001],
+        ')' => [0b100, 0b010, 0b010, 0b010, 0b100],
+        _ => [0b110, 0b001, 0b010, 0b000, 0b010],
+    }
+}
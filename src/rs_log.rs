@@ -0,0 +1,22 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static VERBOSE: AtomicBool = AtomicBool::new(false);
+
+// Diagnostic traces are off unless the binary was run with --verbose
+pub fn set_verbose(verbose: bool) {
+    VERBOSE.store(verbose, Ordering::Relaxed);
+}
+
+pub fn is_verbose() -> bool {
+    VERBOSE.load(Ordering::Relaxed)
+}
+
+// println! that only prints in verbose mode
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)*) => {
+        if $crate::is_verbose() {
+            println!($($arg)*);
+        }
+    };
+}
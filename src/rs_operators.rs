@@ -1,6 +1,7 @@
 use crate::rs_ast::{Operator, Value};
 use crate::rs_error::RSLogoError;
 use crate::rs_stack::Stack;
+use std::cmp::Ordering;
 
 impl Operator {
     pub fn apply(&self, stack: &mut Stack) -> Result<Value, RSLogoError> {
@@ -11,69 +12,189 @@ impl Operator {
             Operator::Subtract => subtract(&left, &right)?,
             Operator::Multiply => multiply(&left, &right)?,
             Operator::Divide => divide(&left, &right)?,
+            Operator::Power => power(&left, &right)?,
             Operator::Equal => equal(&left, &right)?,
             Operator::NotEqual => not_equal(&left, &right)?,
             Operator::GreaterThan => greater_than(&left, &right)?,
             Operator::LessThan => less_than(&left, &right)?,
+            Operator::GreaterEqual => greater_equal(&left, &right)?,
+            Operator::LessEqual => less_equal(&left, &right)?,
             Operator::And => and(&left, &right)?,
             Operator::Or => or(&left, &right)?,
+            Operator::Xor => xor(&left, &right)?,
+            Operator::Max => max(&left, &right)?,
+            Operator::Min => min(&left, &right)?,
         };
         Ok(result)
     }
 }
 
-fn value_to_number(value: &Value) -> Result<i32, RSLogoError> {
+// Numeric view of a value: integers stay exact, anything fractional is a float
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Numeric {
+    Int(i32),
+    Float(f64),
+}
+
+impl Numeric {
+    pub(crate) fn as_f64(self) -> f64 {
+        match self {
+            Numeric::Int(n) => n as f64,
+            Numeric::Float(x) => x,
+        }
+    }
+
+    fn to_value(self) -> Value {
+        match self {
+            Numeric::Int(n) => Value::Number(n),
+            Numeric::Float(x) => Value::Float(x),
+        }
+    }
+}
+
+// Two numbers compare equal when at least one is a float and they differ by
+// no more than this, relative to the larger magnitude (absolute below 1).
+// That absorbs float rounding, so EQ + "0.1 "0.2 "0.3 is TRUE, while
+// integers compare exactly.
+const FLOAT_TOLERANCE: f64 = 1e-9;
+
+// None when either side is NaN, making every comparison with it FALSE
+fn compare_numbers(left: Numeric, right: Numeric) -> Option<Ordering> {
+    match (left, right) {
+        (Numeric::Int(l), Numeric::Int(r)) => Some(l.cmp(&r)),
+        (l, r) => {
+            let (l, r) = (l.as_f64(), r.as_f64());
+            let scale = l.abs().max(r.abs()).max(1.0);
+            if (l - r).abs() <= FLOAT_TOLERANCE * scale {
+                Some(Ordering::Equal)
+            } else {
+                l.partial_cmp(&r)
+            }
+        }
+    }
+}
+
+fn compare(left: &Value, right: &Value) -> Result<Option<Ordering>, RSLogoError> {
+    Ok(compare_numbers(
+        value_to_number(left)?,
+        value_to_number(right)?,
+    ))
+}
+
+// The coercions every operator and command goes through. Booleans, and the
+// words TRUE and FALSE in any case, are 1 and 0 as numbers. Other words are
+// read as integers, or failing that as floats. Lists are never numbers.
+pub(crate) fn value_to_number(value: &Value) -> Result<Numeric, RSLogoError> {
     match value {
-        Value::Number(n) => Ok(*n),
-        Value::String(s) => s.parse::<i32>().map_err(|_| RSLogoError::TypeMismatch),
-        Value::Boolean(b) => Ok(if *b { 1 } else { 0 }),
+        Value::Number(n) => Ok(Numeric::Int(*n)),
+        Value::Float(x) => Ok(Numeric::Float(*x)),
+        Value::String(s) => {
+            if let Some(b) = word_to_bool(s) {
+                Ok(Numeric::Int(b as i32))
+            } else if let Ok(n) = s.parse::<i32>() {
+                Ok(Numeric::Int(n))
+            } else {
+                s.parse::<f64>()
+                    .map(Numeric::Float)
+                    .map_err(|_| RSLogoError::TypeMismatch)
+            }
+        }
+        Value::Boolean(b) => Ok(Numeric::Int(*b as i32)),
+        Value::List(_) => Err(RSLogoError::TypeMismatch),
         Value::Variable(_) => Err(RSLogoError::TypeMismatch), // Variables should be resolved before reaching here
     }
 }
 
-fn value_to_bool(value: &Value) -> Result<bool, RSLogoError> {
+// As a condition, a number is true when it isn't zero, and so is a word that
+// reads as one. TRUE and FALSE are themselves; any other word is false.
+pub(crate) fn value_to_bool(value: &Value) -> Result<bool, RSLogoError> {
     match value {
         Value::Boolean(b) => Ok(*b),
         Value::Number(n) => Ok(*n != 0),
-        Value::String(s) => Ok(s.to_uppercase() == "TRUE"),
+        Value::Float(x) => Ok(*x != 0.0),
+        Value::String(_) => match value_to_number(value) {
+            Ok(n) => Ok(n.as_f64() != 0.0),
+            Err(_) => Ok(false),
+        },
+        // A list has no truth value; say so rather than a bare type mismatch
+        Value::List(_) => Err(RSLogoError::UnexpectedValue {
+            expected: "TRUE, FALSE or a number as a condition".to_string(),
+            got: value.to_string(),
+        }),
         Value::Variable(_) => Err(RSLogoError::TypeMismatch), // Variables should be resolved before reaching here
     }
 }
 
+fn word_to_bool(word: &str) -> Option<bool> {
+    if word.eq_ignore_ascii_case("TRUE") {
+        Some(true)
+    } else if word.eq_ignore_ascii_case("FALSE") {
+        Some(false)
+    } else {
+        None
+    }
+}
+
+// Float results that are no longer finite are treated like integer overflow
+fn float_result(x: f64) -> Result<Value, RSLogoError> {
+    if x.is_finite() {
+        Ok(Value::Float(x))
+    } else {
+        Err(RSLogoError::Overflow)
+    }
+}
+
 fn add(left: &Value, right: &Value) -> Result<Value, RSLogoError> {
-    let left_num = value_to_number(left)?;
-    let right_num = value_to_number(right)?;
-    left_num
-        .checked_add(right_num)
-        .map(Value::Number)
-        .ok_or(RSLogoError::Overflow)
+    match (value_to_number(left)?, value_to_number(right)?) {
+        (Numeric::Int(l), Numeric::Int(r)) => l
+            .checked_add(r)
+            .map(Value::Number)
+            .ok_or(RSLogoError::Overflow),
+        (l, r) => float_result(l.as_f64() + r.as_f64()),
+    }
 }
 
 fn subtract(left: &Value, right: &Value) -> Result<Value, RSLogoError> {
-    let left_num = value_to_number(left)?;
-    let right_num = value_to_number(right)?;
-    left_num
-        .checked_sub(right_num)
-        .map(Value::Number)
-        .ok_or(RSLogoError::Overflow)
+    match (value_to_number(left)?, value_to_number(right)?) {
+        (Numeric::Int(l), Numeric::Int(r)) => l
+            .checked_sub(r)
+            .map(Value::Number)
+            .ok_or(RSLogoError::Overflow),
+        (l, r) => float_result(l.as_f64() - r.as_f64()),
+    }
 }
 
 fn multiply(left: &Value, right: &Value) -> Result<Value, RSLogoError> {
-    let left_num = value_to_number(left)?;
-    let right_num = value_to_number(right)?;
-    left_num
-        .checked_mul(right_num)
-        .map(Value::Number)
-        .ok_or(RSLogoError::Overflow)
+    match (value_to_number(left)?, value_to_number(right)?) {
+        (Numeric::Int(l), Numeric::Int(r)) => l
+            .checked_mul(r)
+            .map(Value::Number)
+            .ok_or(RSLogoError::Overflow),
+        (l, r) => float_result(l.as_f64() * r.as_f64()),
+    }
 }
 
 fn divide(left: &Value, right: &Value) -> Result<Value, RSLogoError> {
-    let left_num = value_to_number(left)?;
-    let right_num = value_to_number(right)?;
-    if right_num == 0 {
-        Err(RSLogoError::DivisionByZero)
-    } else {
-        Ok(Value::Number(left_num / right_num))
+    match (value_to_number(left)?, value_to_number(right)?) {
+        (_, Numeric::Int(0)) => Err(RSLogoError::DivisionByZero),
+        (_, Numeric::Float(0.0)) => Err(RSLogoError::DivisionByZero),
+        // Integer division stays exact only when there is no remainder
+        (Numeric::Int(l), Numeric::Int(r)) if l.checked_rem(r) == Some(0) => {
+            Ok(Value::Number(l / r))
+        }
+        (l, r) => float_result(l.as_f64() / r.as_f64()),
+    }
+}
+
+fn power(left: &Value, right: &Value) -> Result<Value, RSLogoError> {
+    match (value_to_number(left)?, value_to_number(right)?) {
+        (Numeric::Int(base), Numeric::Int(exponent)) if exponent >= 0 => base
+            .checked_pow(exponent as u32)
+            .map(Value::Number)
+            .ok_or(RSLogoError::Overflow),
+        // Negative exponents have no integer result, so they fall back to floats
+        (Numeric::Int(base), Numeric::Int(exponent)) => float_result((base as f64).powi(exponent)),
+        (base, exponent) => float_result(base.as_f64().powf(exponent.as_f64())),
     }
 }
 
@@ -81,36 +202,78 @@ fn equal(left: &Value, right: &Value) -> Result<Value, RSLogoError> {
     match (left, right) {
         (Value::Number(l), Value::Number(r)) => Ok(Value::Boolean(l == r)),
         (Value::String(l), Value::String(r)) => {
-            Ok(Value::Boolean(l.to_uppercase() == r.to_uppercase()))
-        }
-        (Value::Boolean(l), Value::Boolean(r)) => Ok(Value::Boolean(l == r)),
-        (Value::Number(l), Value::String(r)) | (Value::String(r), Value::Number(l)) => {
-            if let Ok(r_num) = r.parse::<i32>() {
-                Ok(Value::Boolean(l == &r_num))
-            } else {
-                Err(RSLogoError::TypeMismatch)
+            match (value_to_number(left), value_to_number(right)) {
+                (Ok(l_num), Ok(r_num)) => Ok(Value::Boolean(
+                    compare_numbers(l_num, r_num) == Some(Ordering::Equal),
+                )),
+                _ => Ok(Value::Boolean(l.to_uppercase() == r.to_uppercase())),
             }
         }
+        (Value::Boolean(l), Value::Boolean(r)) => Ok(Value::Boolean(l == r)),
+        (Value::List(l), Value::List(r)) => Ok(Value::Boolean(l == r)),
+        // Mixed numbers compare by numeric value, so EQ "2.0 "2 is TRUE, and
+        // booleans count as 1 and 0, so EQ TRUE "1 is too
+        (
+            Value::Number(_) | Value::Float(_) | Value::String(_) | Value::Boolean(_),
+            Value::Number(_) | Value::Float(_) | Value::String(_) | Value::Boolean(_),
+        ) => Ok(Value::Boolean(
+            compare(left, right)? == Some(Ordering::Equal),
+        )),
         _ => Err(RSLogoError::TypeMismatch),
     }
 }
 
+// NE is exactly the negation of EQ, so both agree on strings and booleans
 fn not_equal(left: &Value, right: &Value) -> Result<Value, RSLogoError> {
-    let left_num = value_to_number(left)?;
-    let right_num = value_to_number(right)?;
-    Ok(Value::Boolean(left_num != right_num))
+    match equal(left, right)? {
+        Value::Boolean(same) => Ok(Value::Boolean(!same)),
+        _ => Err(RSLogoError::TypeMismatch),
+    }
 }
 
 fn greater_than(left: &Value, right: &Value) -> Result<Value, RSLogoError> {
-    let left_num = value_to_number(left)?;
-    let right_num = value_to_number(right)?;
-    Ok(Value::Boolean(left_num > right_num))
+    Ok(Value::Boolean(matches!(
+        compare(left, right)?,
+        Some(Ordering::Greater)
+    )))
 }
 
 fn less_than(left: &Value, right: &Value) -> Result<Value, RSLogoError> {
-    let left_num = value_to_number(left)?;
-    let right_num = value_to_number(right)?;
-    Ok(Value::Boolean(left_num < right_num))
+    Ok(Value::Boolean(matches!(
+        compare(left, right)?,
+        Some(Ordering::Less)
+    )))
+}
+
+fn greater_equal(left: &Value, right: &Value) -> Result<Value, RSLogoError> {
+    Ok(Value::Boolean(matches!(
+        compare(left, right)?,
+        Some(Ordering::Greater | Ordering::Equal)
+    )))
+}
+
+fn less_equal(left: &Value, right: &Value) -> Result<Value, RSLogoError> {
+    Ok(Value::Boolean(matches!(
+        compare(left, right)?,
+        Some(Ordering::Less | Ordering::Equal)
+    )))
+}
+
+// Ties go to the left operand
+fn max(left: &Value, right: &Value) -> Result<Value, RSLogoError> {
+    let (l, r) = (value_to_number(left)?, value_to_number(right)?);
+    match compare_numbers(l, r) {
+        Some(Ordering::Less) => Ok(r.to_value()),
+        _ => Ok(l.to_value()),
+    }
+}
+
+fn min(left: &Value, right: &Value) -> Result<Value, RSLogoError> {
+    let (l, r) = (value_to_number(left)?, value_to_number(right)?);
+    match compare_numbers(l, r) {
+        Some(Ordering::Greater) => Ok(r.to_value()),
+        _ => Ok(l.to_value()),
+    }
 }
 
 fn and(left: &Value, right: &Value) -> Result<Value, RSLogoError> {
@@ -124,3 +287,9 @@ fn or(left: &Value, right: &Value) -> Result<Value, RSLogoError> {
     let right_bool = value_to_bool(right)?;
     Ok(Value::Boolean(left_bool || right_bool))
 }
+
+fn xor(left: &Value, right: &Value) -> Result<Value, RSLogoError> {
+    let left_bool = value_to_bool(left)?;
+    let right_bool = value_to_bool(right)?;
+    Ok(Value::Boolean(left_bool ^ right_bool))
+}
@@ -1,25 +1,47 @@
-use crate::rs_ast::{Command, Expression, Operator, Program, Value};
+use crate::rs_ast::{Block, Command, Expression, Operator, Program, Span, Value};
 use crate::rs_error::RSLogoError;
 use nom::{
     branch::alt,
-    bytes::complete::{tag, take_while1},
-    character::complete::{char, digit1, line_ending, multispace0, multispace1, not_line_ending},
-    combinator::{all_consuming, map, map_res, opt, recognize, value},
-    error::Error,
-    multi::many0,
+    bytes::complete::{tag, take_till1, take_until, take_while1},
+    character::complete::{
+        char, digit1, line_ending, multispace1, not_line_ending, one_of, satisfy, space1,
+    },
+    combinator::{all_consuming, map, map_res, not, opt, recognize, value},
+    error::{Error, ErrorKind},
+    multi::{many0, many1, separated_list0},
     sequence::{delimited, preceded, terminated, tuple},
     Finish, IResult,
 };
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
 
 fn parse_value(input: &str) -> IResult<&str, Value> {
     alt((
+        map(parse_quoted_word, Value::String),
+        // A hex color such as "#FF8800 keeps its # so SETPENCOLOR can tell
+        // it from a color name
         map(
             preceded(
                 char('"'),
-                take_while1(|c: char| c.is_alphanumeric() || c == '_' || c == '-'),
+                recognize(preceded(
+                    char('#'),
+                    take_while1(|c: char| c.is_alphanumeric()),
+                )),
+            ),
+            |s: &str| Value::String(s.to_string()),
+        ),
+        map(
+            preceded(
+                char('"'),
+                take_while1(|c: char| c.is_alphanumeric() || c == '_' || c == '-' || c == '.'),
             ),
             |s: &str| Value::String(s.to_string()),
         ),
+        map_res(
+            recognize(tuple((opt(char('-')), digit1, char('.'), digit1))),
+            |s: &str| s.parse::<f64>().map(Value::Float),
+        ),
         map_res(recognize(tuple((opt(char('-')), digit1))), |s: &str| {
             s.parse::<i32>().map(Value::Number)
         }),
@@ -30,42 +52,146 @@ fn parse_value(input: &str) -> IResult<&str, Value> {
             ),
             |s: &str| Value::Variable(s.to_string()),
         ),
-        map(alt((tag("TRUE"), tag("FALSE"))), |s: &str| {
+        map(alt((whole_word("TRUE"), whole_word("FALSE"))), |s: &str| {
             Value::Boolean(s == "TRUE")
         }),
     ))(input)
 }
 
+// A word between bars, such as "|hello world|, may hold spaces and
+// punctuation. A backslash escapes a bar, quote or backslash, and \n and \t
+// stand for a newline and a tab.
+fn parse_quoted_word(input: &str) -> IResult<&str, String> {
+    let (rest, _) = tag("\"|")(input)?;
+    let mut word = String::new();
+    let mut chars = rest.char_indices();
+    loop {
+        match chars.next() {
+            Some((i, '|')) => return Ok((&rest[i + 1..], word)),
+            Some((_, '\\')) => match chars.next() {
+                Some((_, 'n')) => word.push('\n'),
+                Some((_, 't')) => word.push('\t'),
+                Some((_, c @ ('|' | '"' | '\\'))) => word.push(c),
+                _ => return Err(nom::Err::Error(Error::new(input, ErrorKind::Escaped))),
+            },
+            Some((_, c)) => word.push(c),
+            // No closing bar
+            None => return Err(nom::Err::Error(Error::new(input, ErrorKind::Char))),
+        }
+    }
+}
+
 fn parse_operator(input: &str) -> IResult<&str, Operator> {
     alt((
         value(Operator::Add, tag("+")),
         value(Operator::Subtract, tag("-")),
         value(Operator::Multiply, tag("*")),
-        value(Operator::Divide, tag("/")),
-        value(Operator::Equal, tag("EQ")),
-        value(Operator::NotEqual, tag("NE")),
-        value(Operator::GreaterThan, tag("GT")),
-        value(Operator::LessThan, tag("LT")),
-        value(Operator::And, tag("AND")),
-        value(Operator::Or, tag("OR")),
+        // "//" and "/*" start comments, not a division
+        value(Operator::Divide, terminated(tag("/"), not(one_of("/*")))),
+        value(Operator::Power, alt((tag("^"), whole_word("POWER")))),
+        value(Operator::Equal, whole_word("EQ")),
+        value(Operator::NotEqual, whole_word("NE")),
+        value(Operator::GreaterEqual, whole_word("GE")),
+        value(Operator::LessEqual, whole_word("LE")),
+        value(Operator::GreaterThan, whole_word("GT")),
+        value(Operator::LessThan, whole_word("LT")),
+        value(Operator::And, whole_word("AND")),
+        value(Operator::Max, whole_word("MAX")),
+        value(Operator::Min, whole_word("MIN")),
+        value(Operator::Or, whole_word("OR")),
+        value(Operator::Xor, whole_word("XOR")),
     ))(input)
 }
 
 fn parse_expression(input: &str) -> IResult<&str, Expression> {
     alt((
         map(parse_value, Expression::Value),
+        // A list of plain values; command blocks never appear in expression position
+        map(
+            delimited(
+                terminated(char('['), blank),
+                separated_list0(blank1, parse_value),
+                preceded(blank, char(']')),
+            ),
+            |items| Expression::Value(Value::List(items)),
+        ),
+        // Parentheses only group; they don't change the prefix operator syntax
+        delimited(
+            terminated(char('('), blank),
+            parse_expression,
+            preceded(blank, char(')')),
+        ),
         map(
             tuple((
                 parse_operator,
-                multispace1,
+                blank1,
                 parse_expression,
-                multispace1,
+                blank1,
                 parse_expression,
             )),
             |(op, _, left, _, right)| Expression::BinaryOp(op, Box::new(left), Box::new(right)),
         ),
         map(
-            alt((tag("XCOR"), tag("YCOR"), tag("HEADING"), tag("COLOR"))),
+            tuple((
+                alt((
+                    whole_word("SQRT"),
+                    whole_word("SIN"),
+                    whole_word("COS"),
+                    whole_word("TAN"),
+                    whole_word("RANDOM"),
+                    whole_word("PICK"),
+                    whole_word("SUM"),
+                    whole_word("PRODUCT"),
+                    whole_word("INT"),
+                    whole_word("ROUND"),
+                    whole_word("ABS"),
+                    whole_word("MINUS"),
+                    whole_word("DEFINEDP"),
+                    whole_word("VARDEFINEDP"),
+                )),
+                blank1,
+                parse_expression,
+            )),
+            |(name, _, arg)| Expression::Function(name.to_string(), vec![arg]),
+        ),
+        map(
+            tuple((
+                whole_word("TOWARDS"),
+                blank1,
+                parse_expression,
+                blank1,
+                parse_expression,
+            )),
+            |(name, _, x, _, y)| Expression::Function(name.to_string(), vec![x, y]),
+        ),
+        // Procedure call in expression position; arguments run to the end of the line
+        map(
+            tuple((
+                whole_word("CALL"),
+                space1,
+                take_while1(|c: char| c.is_alphanumeric() || c == '_'),
+                many0(preceded(space1, parse_expression)),
+            )),
+            |(_, _, name, arguments)| Expression::Call(name.to_string(), arguments),
+        ),
+        map(
+            alt((
+                whole_word("XCOR"),
+                whole_word("YCOR"),
+                whole_word("POS"),
+                whole_word("HEADING"),
+                whole_word("COLORNAME"),
+                whole_word("COLOR"),
+                whole_word("PENSIZE"),
+                whole_word("PENDOWNP"),
+                whole_word("PENRED"),
+                whole_word("PENGREEN"),
+                whole_word("PENBLUE"),
+                whole_word("REPCOUNT"),
+                whole_word("NEXTCOLOR"),
+                whole_word("WIDTH"),
+                whole_word("HEIGHT"),
+            )),
             |s: &str| Expression::Query(s.to_string()),
         ),
     ))(input)
@@ -94,41 +220,54 @@ fn parse_parameter(input: &str) -> IResult<&str, (String, bool)> {
 
 fn parse_procedure_definition(input: &str) -> IResult<&str, Result<Command, RSLogoError>> {
     // Parse "TO" and procedure name
-    let (remaining, _) = tag("TO")(input)?;
-    let (remaining, _) = multispace1(remaining)?;
+    let (remaining, _) = whole_word("TO")(input)?;
+    let (remaining, _) = blank1(remaining)?;
     let (remaining, name) = take_while1(|c: char| c.is_alphanumeric() || c == '_')(remaining)?;
 
     // Parse parameters - now handling both variable and literal parameters
     let mut parameters = Vec::new();
+    let mut rest = None;
     let mut current = remaining;
 
     loop {
         // Skip whitespace
-        let (next, _) = multispace0(current)?;
+        let (next, _) = blank(current)?;
 
         // Try to parse a parameter
         match parse_parameter(next) {
             Ok((remaining, param)) => {
-                parameters.push(param.0);
-                current = remaining;
+                // Only the last parameter may collect the remaining arguments
+                if rest.is_some() {
+                    return Ok((
+                        remaining,
+                        Err(RSLogoError::ParseError {
+                            input: input.to_string(),
+                            span: (input.len() - next.len(), next.len() - remaining.len()),
+                            message: format!(
+                                "Procedure '{}' has a parameter after its rest parameter; \
+                                 a rest parameter must come last",
+                                name
+                            ),
+                        }),
+                    ));
+                }
+                current = match tag::<&str, &str, Error<&str>>("...")(remaining) {
+                    Ok((remaining, _)) => {
+                        rest = Some(param.0);
+                        remaining
+                    }
+                    Err(_) => {
+                        parameters.push(param.0);
+                        remaining
+                    }
+                };
             }
             Err(_) => break,
         }
     }
 
     // Skip whitespace and comments after parameters
-    let (current, _) = many0(alt((
-        value((), multispace1),
-        value((), line_ending::<&str, Error<&str>>),
-        value(
-            (),
-            tuple((
-                tag("//"),
-                not_line_ending,
-                opt(line_ending::<&str, Error<&str>>),
-            )),
-        ),
-    )))(current)?;
+    let (current, _) = blank(current)?;
 
     // Keep track of the start position for error reporting
     let start_pos = input.len() - current.len();
@@ -141,21 +280,10 @@ fn parse_procedure_definition(input: &str) -> IResult<&str, Result<Command, RSLo
 
     loop {
         // Skip whitespace, newlines and comments
-        let (next, _) = many0(alt((
-            value((), multispace1),
-            value((), line_ending::<&str, Error<&str>>),
-            value(
-                (),
-                tuple((
-                    tag("//"),
-                    not_line_ending,
-                    opt(line_ending::<&str, Error<&str>>),
-                )),
-            ),
-        )))(current_pos)?;
+        let (next, _) = blank(current_pos)?;
 
         // Check for END
-        if let Ok((remaining, _)) = tag::<&str, &str, Error<&str>>("END")(next) {
+        if let Ok((remaining, _)) = whole_word("END")(next) {
             current_pos = remaining;
             found_end = true;
             break;
@@ -165,7 +293,7 @@ fn parse_procedure_definition(input: &str) -> IResult<&str, Result<Command, RSLo
         match parse_regular_command(next) {
             Ok((remaining, cmd)) => {
                 if let Ok(cmd) = cmd {
-                    commands.push(cmd);
+                    commands.push(locate(next, remaining, cmd));
                     command_count += 1;
                     current_pos = remaining;
                 } else {
@@ -186,7 +314,7 @@ fn parse_procedure_definition(input: &str) -> IResult<&str, Result<Command, RSLo
         return Ok((
             current_pos,
             Err(RSLogoError::ParseError {
-                input: input[start_pos..].to_string(),
+                input: input.to_string(),
                 span: (start_pos, input.len() - start_pos),
                 message: format!(
                     "Unterminated procedure definition '{}': Expected 'END' keyword after {} commands",
@@ -202,7 +330,8 @@ fn parse_procedure_definition(input: &str) -> IResult<&str, Result<Command, RSLo
         Ok(Command::ProcedureDefinition {
             name: name.to_string(),
             parameters,
-            body: commands,
+            rest,
+            body: Rc::new(commands),
         }),
     ))
 }
@@ -218,7 +347,7 @@ fn parse_procedure_call(input: &str) -> IResult<&str, Result<Command, RSLogoErro
         )));
     }
 
-    let (remaining, arguments) = many0(preceded(multispace1, parse_expression))(remaining)?;
+    let (remaining, arguments) = many0(preceded(blank1, parse_expression))(remaining)?;
 
     Ok((
         remaining,
@@ -230,32 +359,32 @@ fn parse_procedure_call(input: &str) -> IResult<&str, Result<Command, RSLogoErro
 }
 
 fn parse_make_command(input: &str) -> IResult<&str, Result<Command, RSLogoError>> {
-    let (input, _) = tag("MAKE")(input)?;
-    let (input, _) = multispace1(input)?;
+    let (input, _) = whole_word("MAKE")(input)?;
+    let (input, _) = blank1(input)?;
     let (input, name_expr) = parse_expression(input)?;
-    let (input, _) = multispace1(input)?;
+    let (input, _) = blank1(input)?;
     let (input, value_expr) = parse_expression(input)?;
 
     Ok((input, Ok(Command::Make(name_expr, value_expr))))
 }
 
-fn parse_command_block(input: &str) -> IResult<&str, Result<Vec<Command>, RSLogoError>> {
+fn parse_command_block(input: &str) -> IResult<&str, Result<Block, RSLogoError>> {
     let (remaining, commands) = delimited(
         char('['),
-        many0(delimited(multispace0, parse_command, multispace0)),
+        preceded(blank, many0(terminated(parse_command, blank))),
         char(']'),
     )(input)?;
 
     let result: Result<Vec<Command>, RSLogoError> = commands.into_iter().collect();
-    Ok((remaining, result))
+    Ok((remaining, result.map(Rc::new)))
 }
 
 fn parse_if_command(input: &str) -> IResult<&str, Result<Command, RSLogoError>> {
     let (remaining, (_, _, condition, _, body)) = tuple((
-        tag("IF"),
-        multispace1,
+        whole_word("IF"),
+        blank1,
         parse_expression,
-        multispace0,
+        blank,
         parse_command_block,
     ))(input)?;
 
@@ -263,12 +392,27 @@ fn parse_if_command(input: &str) -> IResult<&str, Result<Command, RSLogoError>>
     Ok((remaining, result))
 }
 
+fn parse_ifelse_command(input: &str) -> IResult<&str, Result<Command, RSLogoError>> {
+    let (remaining, (_, _, condition, _, then_body, _, else_body)) = tuple((
+        whole_word("IFELSE"),
+        blank1,
+        parse_expression,
+        blank,
+        parse_command_block,
+        blank,
+        parse_command_block,
+    ))(input)?;
+
+    let result = then_body.and_then(|t| else_body.map(|e| Command::IfElse(condition, t, e)));
+    Ok((remaining, result))
+}
+
 fn parse_while_command(input: &str) -> IResult<&str, Result<Command, RSLogoError>> {
     let (remaining, (_, _, condition, _, body)) = tuple((
-        tag("WHILE"),
-        multispace1,
+        whole_word("WHILE"),
+        blank1,
         parse_expression,
-        multispace0,
+        blank,
         parse_command_block,
     ))(input)?;
 
@@ -276,36 +420,144 @@ fn parse_while_command(input: &str) -> IResult<&str, Result<Command, RSLogoError
     Ok((remaining, result))
 }
 
-fn parse_regular_command(input: &str) -> IResult<&str, Result<Command, RSLogoError>> {
+fn parse_do_until_command(input: &str) -> IResult<&str, Result<Command, RSLogoError>> {
+    let (remaining, (_, _, body, _, _, _, condition)) = tuple((
+        whole_word("DO"),
+        blank,
+        parse_command_block,
+        blank,
+        whole_word("UNTIL"),
+        blank1,
+        parse_expression,
+    ))(input)?;
+
+    let result = body.map(|b| Command::DoUntil(b, condition));
+    Ok((remaining, result))
+}
+
+fn parse_for_command(input: &str) -> IResult<&str, Result<Command, RSLogoError>> {
+    let (remaining, (_, _, var, _, from, _, to, _, step, _, body)) = tuple((
+        whole_word("FOR"),
+        blank1,
+        preceded(
+            alt((char(':'), char('"'))),
+            take_while1(|c: char| c.is_alphanumeric() || c == '_'),
+        ),
+        blank1,
+        parse_expression,
+        blank1,
+        parse_expression,
+        blank1,
+        parse_expression,
+        blank,
+        parse_command_block,
+    ))(input)?;
+
+    let result = body.map(|b| Command::For {
+        var: var.to_string(),
+        from,
+        to,
+        step,
+        body: b,
+    });
+    Ok((remaining, result))
+}
+
+// FOREACH :item [ "1 "2 "3 ] [ ... ]; the list can be any expression that
+// evaluates to a list
+fn parse_foreach_command(input: &str) -> IResult<&str, Result<Command, RSLogoError>> {
+    let (remaining, (_, _, var, _, list, _, body)) = tuple((
+        whole_word("FOREACH"),
+        blank1,
+        preceded(
+            alt((char(':'), char('"'))),
+            take_while1(|c: char| c.is_alphanumeric() || c == '_'),
+        ),
+        blank1,
+        parse_expression,
+        blank,
+        parse_command_block,
+    ))(input)?;
+
+    let result = body.map(|b| Command::ForEach {
+        var: var.to_string(),
+        list,
+        body: b,
+    });
+    Ok((remaining, result))
+}
+
+fn parse_control_command(input: &str) -> IResult<&str, Result<Command, RSLogoError>> {
     alt((
         map(
-            tuple((tag("PENUP"), opt(preceded(multispace1, parse_expression)))),
+            tuple((
+                whole_word("OUTPUT"),
+                blank1,
+                parse_expression,
+                opt(preceded(blank1, parse_expression)),
+            )),
+            |(cmd, _, expr, extra)| match extra {
+                Some(_) => Err(RSLogoError::InvalidArgument {
+                    command: cmd.to_string(),
+                    argument: "".to_string(),
+                    expected: "only one argument".to_string(),
+                }),
+                None => Ok(Command::Output(expr)),
+            },
+        ),
+        map(
+            tuple((whole_word("STOP"), opt(preceded(blank1, parse_expression)))),
             |(cmd, expr)| match expr {
                 Some(_) => Err(RSLogoError::InvalidArgument {
                     command: cmd.to_string(),
                     argument: "".to_string(),
                     expected: "no arguments".to_string(),
                 }),
-                None => Ok(Command::PenUp),
+                None => Ok(Command::Stop),
             },
         ),
         map(
-            tuple((tag("PENDOWN"), opt(preceded(multispace1, parse_expression)))),
+            tuple((
+                whole_word("CONTINUE"),
+                opt(preceded(blank1, parse_expression)),
+            )),
             |(cmd, expr)| match expr {
                 Some(_) => Err(RSLogoError::InvalidArgument {
                     command: cmd.to_string(),
                     argument: "".to_string(),
                     expected: "no arguments".to_string(),
                 }),
-                None => Ok(Command::PenDown),
+                None => Ok(Command::Continue),
+            },
+        ),
+        map(
+            tuple((whole_word("BREAK"), opt(preceded(blank1, parse_expression)))),
+            |(cmd, expr)| match expr {
+                Some(_) => Err(RSLogoError::InvalidArgument {
+                    command: cmd.to_string(),
+                    argument: "".to_string(),
+                    expected: "no arguments".to_string(),
+                }),
+                None => Ok(Command::Break),
+            },
+        ),
+        map(
+            tuple((whole_word("RESET"), opt(preceded(blank1, parse_expression)))),
+            |(cmd, expr)| match expr {
+                Some(_) => Err(RSLogoError::InvalidArgument {
+                    command: cmd.to_string(),
+                    argument: "".to_string(),
+                    expected: "no arguments".to_string(),
+                }),
+                None => Ok(Command::Reset),
             },
         ),
         map(
             tuple((
-                tag("FORWARD"),
-                multispace1,
+                whole_word("ASSERT"),
+                blank1,
                 parse_expression,
-                opt(preceded(multispace1, parse_expression)),
+                opt(preceded(blank1, parse_expression)),
             )),
             |(cmd, _, expr, extra)| match extra {
                 Some(_) => Err(RSLogoError::InvalidArgument {
@@ -313,15 +565,35 @@ fn parse_regular_command(input: &str) -> IResult<&str, Result<Command, RSLogoErr
                     argument: "".to_string(),
                     expected: "only one argument".to_string(),
                 }),
-                None => Ok(Command::Forward(expr)),
+                None => Ok(Command::Assert(expr)),
             },
         ),
+        parse_ifelse_command,
+        parse_if_command,
+        parse_while_command,
+        parse_do_until_command,
+        parse_foreach_command,
+        parse_for_command,
+    ))(input)
+}
+
+// A keyword that has to end at a word boundary, so PU doesn't match the
+// start of PUSHTURTLE or a procedure name, and END doesn't match ENDFILL.
+fn whole_word<'a>(word: &'static str) -> impl FnMut(&'a str) -> IResult<&'a str, &'a str> {
+    terminated(
+        tag(word),
+        not(satisfy(|c: char| c.is_alphanumeric() || c == '_')),
+    )
+}
+
+fn parse_movement_command(input: &str) -> IResult<&str, Result<Command, RSLogoError>> {
+    alt((
         map(
             tuple((
-                tag("BACK"),
-                multispace1,
+                alt((whole_word("FORWARD"), whole_word("FD"))),
+                blank1,
                 parse_expression,
-                opt(preceded(multispace1, parse_expression)),
+                opt(preceded(blank1, parse_expression)),
             )),
             |(cmd, _, expr, extra)| match extra {
                 Some(_) => Err(RSLogoError::InvalidArgument {
@@ -329,15 +601,15 @@ fn parse_regular_command(input: &str) -> IResult<&str, Result<Command, RSLogoErr
                     argument: "".to_string(),
                     expected: "only one argument".to_string(),
                 }),
-                None => Ok(Command::Back(expr)),
+                None => Ok(Command::Forward(expr)),
             },
         ),
         map(
             tuple((
-                tag("LEFT"),
-                multispace1,
+                alt((whole_word("BACK"), whole_word("BK"))),
+                blank1,
                 parse_expression,
-                opt(preceded(multispace1, parse_expression)),
+                opt(preceded(blank1, parse_expression)),
             )),
             |(cmd, _, expr, extra)| match extra {
                 Some(_) => Err(RSLogoError::InvalidArgument {
@@ -345,15 +617,15 @@ fn parse_regular_command(input: &str) -> IResult<&str, Result<Command, RSLogoErr
                     argument: "".to_string(),
                     expected: "only one argument".to_string(),
                 }),
-                None => Ok(Command::Left(expr)),
+                None => Ok(Command::Back(expr)),
             },
         ),
         map(
             tuple((
-                tag("RIGHT"),
-                multispace1,
+                alt((whole_word("LEFT"), whole_word("LT"))),
+                blank1,
                 parse_expression,
-                opt(preceded(multispace1, parse_expression)),
+                opt(preceded(blank1, parse_expression)),
             )),
             |(cmd, _, expr, extra)| match extra {
                 Some(_) => Err(RSLogoError::InvalidArgument {
@@ -361,15 +633,15 @@ fn parse_regular_command(input: &str) -> IResult<&str, Result<Command, RSLogoErr
                     argument: "".to_string(),
                     expected: "only one argument".to_string(),
                 }),
-                None => Ok(Command::Right(expr)),
+                None => Ok(Command::Left(expr)),
             },
         ),
         map(
             tuple((
-                tag("SETPENCOLOR"),
-                multispace1,
+                alt((whole_word("RIGHT"), whole_word("RT"))),
+                blank1,
                 parse_expression,
-                opt(preceded(multispace1, parse_expression)),
+                opt(preceded(blank1, parse_expression)),
             )),
             |(cmd, _, expr, extra)| match extra {
                 Some(_) => Err(RSLogoError::InvalidArgument {
@@ -377,15 +649,15 @@ fn parse_regular_command(input: &str) -> IResult<&str, Result<Command, RSLogoErr
                     argument: "".to_string(),
                     expected: "only one argument".to_string(),
                 }),
-                None => Ok(Command::SetPenColor(expr)),
+                None => Ok(Command::Right(expr)),
             },
         ),
         map(
             tuple((
-                tag("TURN"),
-                multispace1,
+                whole_word("TURN"),
+                blank1,
                 parse_expression,
-                opt(preceded(multispace1, parse_expression)),
+                opt(preceded(blank1, parse_expression)),
             )),
             |(cmd, _, expr, extra)| match extra {
                 Some(_) => Err(RSLogoError::InvalidArgument {
@@ -398,10 +670,10 @@ fn parse_regular_command(input: &str) -> IResult<&str, Result<Command, RSLogoErr
         ),
         map(
             tuple((
-                tag("SETHEADING"),
-                multispace1,
+                whole_word("SETHEADING"),
+                blank1,
                 parse_expression,
-                opt(preceded(multispace1, parse_expression)),
+                opt(preceded(blank1, parse_expression)),
             )),
             |(cmd, _, expr, extra)| match extra {
                 Some(_) => Err(RSLogoError::InvalidArgument {
@@ -414,10 +686,10 @@ fn parse_regular_command(input: &str) -> IResult<&str, Result<Command, RSLogoErr
         ),
         map(
             tuple((
-                tag("SETX"),
-                multispace1,
+                whole_word("SETX"),
+                blank1,
                 parse_expression,
-                opt(preceded(multispace1, parse_expression)),
+                opt(preceded(blank1, parse_expression)),
             )),
             |(cmd, _, expr, extra)| match extra {
                 Some(_) => Err(RSLogoError::InvalidArgument {
@@ -430,10 +702,10 @@ fn parse_regular_command(input: &str) -> IResult<&str, Result<Command, RSLogoErr
         ),
         map(
             tuple((
-                tag("SETY"),
-                multispace1,
+                whole_word("SETY"),
+                blank1,
                 parse_expression,
-                opt(preceded(multispace1, parse_expression)),
+                opt(preceded(blank1, parse_expression)),
             )),
             |(cmd, _, expr, extra)| match extra {
                 Some(_) => Err(RSLogoError::InvalidArgument {
@@ -444,11 +716,410 @@ fn parse_regular_command(input: &str) -> IResult<&str, Result<Command, RSLogoErr
                 None => Ok(Command::SetY(expr)),
             },
         ),
+        map(
+            tuple((
+                whole_word("JUMPX"),
+                blank1,
+                parse_expression,
+                opt(preceded(blank1, parse_expression)),
+            )),
+            |(cmd, _, expr, extra)| match extra {
+                Some(_) => Err(RSLogoError::InvalidArgument {
+                    command: cmd.to_string(),
+                    argument: "".to_string(),
+                    expected: "only one argument".to_string(),
+                }),
+                None => Ok(Command::JumpX(expr)),
+            },
+        ),
+        map(
+            tuple((
+                whole_word("JUMPY"),
+                blank1,
+                parse_expression,
+                opt(preceded(blank1, parse_expression)),
+            )),
+            |(cmd, _, expr, extra)| match extra {
+                Some(_) => Err(RSLogoError::InvalidArgument {
+                    command: cmd.to_string(),
+                    argument: "".to_string(),
+                    expected: "only one argument".to_string(),
+                }),
+                None => Ok(Command::JumpY(expr)),
+            },
+        ),
+        map(
+            tuple((
+                whole_word("ARC"),
+                blank1,
+                parse_expression,
+                blank1,
+                parse_expression,
+                opt(preceded(blank1, parse_expression)),
+            )),
+            |(cmd, _, angle, _, radius, extra)| match extra {
+                Some(_) => Err(RSLogoError::InvalidArgument {
+                    command: cmd.to_string(),
+                    argument: "".to_string(),
+                    expected: "only two arguments".to_string(),
+                }),
+                None => Ok(Command::Arc(angle, radius)),
+            },
+        ),
+        map(
+            tuple((
+                whole_word("MOVEBY"),
+                blank1,
+                parse_expression,
+                blank1,
+                parse_expression,
+                opt(preceded(blank1, parse_expression)),
+            )),
+            |(cmd, _, dx, _, dy, extra)| match extra {
+                Some(_) => Err(RSLogoError::InvalidArgument {
+                    command: cmd.to_string(),
+                    argument: "".to_string(),
+                    expected: "only two arguments".to_string(),
+                }),
+                None => Ok(Command::MoveBy(dx, dy)),
+            },
+        ),
+        map(
+            tuple((
+                whole_word("SETPOS"),
+                blank1,
+                parse_expression,
+                opt(preceded(blank1, parse_expression)),
+            )),
+            |(cmd, _, expr, extra)| match extra {
+                Some(_) => Err(RSLogoError::InvalidArgument {
+                    command: cmd.to_string(),
+                    argument: "".to_string(),
+                    expected: "a single [x y] list".to_string(),
+                }),
+                None => Ok(Command::SetPos(expr)),
+            },
+        ),
+    ))(input)
+}
+
+fn parse_turtle_command(input: &str) -> IResult<&str, Result<Command, RSLogoError>> {
+    alt((
+        map(
+            tuple((whole_word("HOME"), opt(preceded(blank1, parse_expression)))),
+            |(cmd, expr)| match expr {
+                Some(_) => Err(RSLogoError::InvalidArgument {
+                    command: cmd.to_string(),
+                    argument: "".to_string(),
+                    expected: "no arguments".to_string(),
+                }),
+                None => Ok(Command::Home),
+            },
+        ),
+        map(
+            tuple((
+                alt((whole_word("CLEARSCREEN"), whole_word("CS"))),
+                opt(preceded(blank1, parse_expression)),
+            )),
+            |(cmd, expr)| match expr {
+                Some(_) => Err(RSLogoError::InvalidArgument {
+                    command: cmd.to_string(),
+                    argument: "".to_string(),
+                    expected: "no arguments".to_string(),
+                }),
+                None => Ok(Command::ClearScreen),
+            },
+        ),
+        map(
+            tuple((
+                whole_word("PUSHTURTLE"),
+                opt(preceded(blank1, parse_expression)),
+            )),
+            |(cmd, expr)| match expr {
+                Some(_) => Err(RSLogoError::InvalidArgument {
+                    command: cmd.to_string(),
+                    argument: "".to_string(),
+                    expected: "no arguments".to_string(),
+                }),
+                None => Ok(Command::PushTurtle),
+            },
+        ),
+        map(
+            tuple((
+                whole_word("POPTURTLE"),
+                opt(preceded(blank1, parse_expression)),
+            )),
+            |(cmd, expr)| match expr {
+                Some(_) => Err(RSLogoError::InvalidArgument {
+                    command: cmd.to_string(),
+                    argument: "".to_string(),
+                    expected: "no arguments".to_string(),
+                }),
+                None => Ok(Command::PopTurtle),
+            },
+        ),
+        map(
+            tuple((
+                whole_word("SHOWTURTLE"),
+                opt(preceded(blank1, parse_expression)),
+            )),
+            |(cmd, expr)| match expr {
+                Some(_) => Err(RSLogoError::InvalidArgument {
+                    command: cmd.to_string(),
+                    argument: "".to_string(),
+                    expected: "no arguments".to_string(),
+                }),
+                None => Ok(Command::ShowTurtle),
+            },
+        ),
+        map(
+            tuple((
+                whole_word("HIDETURTLE"),
+                opt(preceded(blank1, parse_expression)),
+            )),
+            |(cmd, expr)| match expr {
+                Some(_) => Err(RSLogoError::InvalidArgument {
+                    command: cmd.to_string(),
+                    argument: "".to_string(),
+                    expected: "no arguments".to_string(),
+                }),
+                None => Ok(Command::HideTurtle),
+            },
+        ),
+        map(
+            tuple((
+                whole_word("WAIT"),
+                blank1,
+                parse_expression,
+                opt(preceded(blank1, parse_expression)),
+            )),
+            |(cmd, _, expr, extra)| match extra {
+                Some(_) => Err(RSLogoError::InvalidArgument {
+                    command: cmd.to_string(),
+                    argument: "".to_string(),
+                    expected: "only one argument".to_string(),
+                }),
+                None => Ok(Command::Wait(expr)),
+            },
+        ),
+        map(
+            tuple((
+                whole_word("SETSPEED"),
+                blank1,
+                parse_expression,
+                opt(preceded(blank1, parse_expression)),
+            )),
+            |(cmd, _, expr, extra)| match extra {
+                Some(_) => Err(RSLogoError::InvalidArgument {
+                    command: cmd.to_string(),
+                    argument: "".to_string(),
+                    expected: "only one argument".to_string(),
+                }),
+                None => Ok(Command::SetSpeed(expr)),
+            },
+        ),
+        map(
+            tuple((
+                whole_word("LABEL"),
+                blank1,
+                parse_expression,
+                opt(preceded(blank1, parse_expression)),
+            )),
+            |(cmd, _, expr, extra)| match extra {
+                Some(_) => Err(RSLogoError::InvalidArgument {
+                    command: cmd.to_string(),
+                    argument: "".to_string(),
+                    expected: "only one argument".to_string(),
+                }),
+                None => Ok(Command::Label(expr)),
+            },
+        ),
+        map(
+            tuple((
+                whole_word("SETFONTSIZE"),
+                blank1,
+                parse_expression,
+                opt(preceded(blank1, parse_expression)),
+            )),
+            |(cmd, _, expr, extra)| match extra {
+                Some(_) => Err(RSLogoError::InvalidArgument {
+                    command: cmd.to_string(),
+                    argument: "".to_string(),
+                    expected: "only one argument".to_string(),
+                }),
+                None => Ok(Command::SetFontSize(expr)),
+            },
+        ),
+    ))(input)
+}
+
+fn parse_pen_command(input: &str) -> IResult<&str, Result<Command, RSLogoError>> {
+    alt((
+        map(
+            tuple((
+                whole_word("SETPENCOLOR"),
+                blank1,
+                parse_expression,
+                opt(preceded(blank1, parse_expression)),
+            )),
+            |(cmd, _, expr, extra)| match extra {
+                Some(_) => Err(RSLogoError::InvalidArgument {
+                    command: cmd.to_string(),
+                    argument: "".to_string(),
+                    expected: "only one argument".to_string(),
+                }),
+                None => Ok(Command::SetPenColor(expr)),
+            },
+        ),
+        map(
+            tuple((
+                whole_word("SETPENSIZE"),
+                blank1,
+                parse_expression,
+                opt(preceded(blank1, parse_expression)),
+            )),
+            |(cmd, _, expr, extra)| match extra {
+                Some(_) => Err(RSLogoError::InvalidArgument {
+                    command: cmd.to_string(),
+                    argument: "".to_string(),
+                    expected: "only one argument".to_string(),
+                }),
+                None => Ok(Command::SetPenSize(expr)),
+            },
+        ),
+        map(
+            tuple((
+                whole_word("SETRGB"),
+                blank1,
+                parse_expression,
+                blank1,
+                parse_expression,
+                blank1,
+                parse_expression,
+                opt(preceded(blank1, parse_expression)),
+            )),
+            |(cmd, _, red, _, green, _, blue, extra)| match extra {
+                Some(_) => Err(RSLogoError::InvalidArgument {
+                    command: cmd.to_string(),
+                    argument: "".to_string(),
+                    expected: "only three arguments".to_string(),
+                }),
+                None => Ok(Command::SetRGB(red, green, blue)),
+            },
+        ),
+        map(
+            tuple((
+                whole_word("BEGINFILL"),
+                opt(preceded(blank1, parse_expression)),
+            )),
+            |(cmd, expr)| match expr {
+                Some(_) => Err(RSLogoError::InvalidArgument {
+                    command: cmd.to_string(),
+                    argument: "".to_string(),
+                    expected: "no arguments".to_string(),
+                }),
+                None => Ok(Command::BeginFill),
+            },
+        ),
+        map(
+            tuple((
+                whole_word("ENDFILL"),
+                opt(preceded(blank1, parse_expression)),
+            )),
+            |(cmd, expr)| match expr {
+                Some(_) => Err(RSLogoError::InvalidArgument {
+                    command: cmd.to_string(),
+                    argument: "".to_string(),
+                    expected: "no arguments".to_string(),
+                }),
+                None => Ok(Command::EndFill),
+            },
+        ),
+        map(
+            tuple((
+                whole_word("PENREVERSE"),
+                opt(preceded(blank1, parse_expression)),
+            )),
+            |(cmd, expr)| match expr {
+                Some(_) => Err(RSLogoError::InvalidArgument {
+                    command: cmd.to_string(),
+                    argument: "".to_string(),
+                    expected: "no arguments".to_string(),
+                }),
+                None => Ok(Command::PenReverse),
+            },
+        ),
+        map(
+            tuple((
+                whole_word("GROUP"),
+                blank1,
+                parse_expression,
+                opt(preceded(blank1, parse_expression)),
+            )),
+            |(cmd, _, expr, extra)| match extra {
+                Some(_) => Err(RSLogoError::InvalidArgument {
+                    command: cmd.to_string(),
+                    argument: "".to_string(),
+                    expected: "only one argument".to_string(),
+                }),
+                None => Ok(Command::BeginGroup(expr)),
+            },
+        ),
+        map(
+            tuple((
+                whole_word("ENDGROUP"),
+                opt(preceded(blank1, parse_expression)),
+            )),
+            |(cmd, expr)| match expr {
+                Some(_) => Err(RSLogoError::InvalidArgument {
+                    command: cmd.to_string(),
+                    argument: "".to_string(),
+                    expected: "no arguments".to_string(),
+                }),
+                None => Ok(Command::EndGroup),
+            },
+        ),
+    ))(input)
+}
+
+fn parse_regular_command(input: &str) -> IResult<&str, Result<Command, RSLogoError>> {
+    alt((
+        map(
+            tuple((
+                alt((whole_word("PENUP"), whole_word("PU"))),
+                opt(preceded(blank1, parse_expression)),
+            )),
+            |(cmd, expr)| match expr {
+                Some(_) => Err(RSLogoError::InvalidArgument {
+                    command: cmd.to_string(),
+                    argument: "".to_string(),
+                    expected: "no arguments".to_string(),
+                }),
+                None => Ok(Command::PenUp),
+            },
+        ),
+        map(
+            tuple((
+                alt((whole_word("PENDOWN"), whole_word("PD"))),
+                opt(preceded(blank1, parse_expression)),
+            )),
+            |(cmd, expr)| match expr {
+                Some(_) => Err(RSLogoError::InvalidArgument {
+                    command: cmd.to_string(),
+                    argument: "".to_string(),
+                    expected: "no arguments".to_string(),
+                }),
+                None => Ok(Command::PenDown),
+            },
+        ),
+        parse_movement_command,
+        parse_turtle_command,
+        parse_pen_command,
         parse_make_command,
         map(
             tuple((
-                tag("ADDASSIGN"),
-                multispace1,
+                whole_word("ADDASSIGN"),
+                blank1,
                 alt((
                     preceded(
                         char('"'),
@@ -459,9 +1130,9 @@ fn parse_regular_command(input: &str) -> IResult<&str, Result<Command, RSLogoErr
                         take_while1(|c: char| c.is_alphanumeric() || c == '_'),
                     ),
                 )),
-                multispace1,
+                blank1,
                 parse_expression,
-                opt(preceded(multispace1, parse_expression)),
+                opt(preceded(blank1, parse_expression)),
             )),
             |(cmd, _, var_name, _, expr, extra)| match extra {
                 Some(_) => Err(RSLogoError::InvalidArgument {
@@ -472,8 +1143,64 @@ fn parse_regular_command(input: &str) -> IResult<&str, Result<Command, RSLogoErr
                 None => Ok(Command::AddAssign(var_name.to_string(), expr)),
             },
         ),
-        parse_if_command,
-        parse_while_command,
+        map(
+            tuple((
+                whole_word("LOCAL"),
+                blank1,
+                preceded(
+                    char('"'),
+                    take_while1(|c: char| c.is_alphanumeric() || c == '_'),
+                ),
+            )),
+            |(_, _, name)| Ok(Command::Local(name.to_string())),
+        ),
+        map(
+            tuple((
+                whole_word("GLOBAL"),
+                blank1,
+                preceded(
+                    char('"'),
+                    take_while1(|c: char| c.is_alphanumeric() || c == '_'),
+                ),
+            )),
+            |(_, _, name)| Ok(Command::Global(name.to_string())),
+        ),
+        map(
+            tuple((
+                whole_word("ERASE"),
+                blank1,
+                preceded(
+                    char('"'),
+                    take_while1(|c: char| c.is_alphanumeric() || c == '_'),
+                ),
+            )),
+            |(_, _, name)| Ok(Command::Erase(name.to_string())),
+        ),
+        map(
+            tuple((
+                whole_word("INCLUDE"),
+                blank1,
+                preceded(char('"'), take_till1(|c: char| c.is_whitespace())),
+            )),
+            |(_, _, path)| Ok(Command::Include(path.to_string())),
+        ),
+        map(
+            tuple((
+                whole_word("PRINT"),
+                blank1,
+                parse_expression,
+                opt(preceded(blank1, parse_expression)),
+            )),
+            |(cmd, _, expr, extra)| match extra {
+                Some(_) => Err(RSLogoError::InvalidArgument {
+                    command: cmd.to_string(),
+                    argument: "".to_string(),
+                    expected: "only one argument".to_string(),
+                }),
+                None => Ok(Command::Print(expr)),
+            },
+        ),
+        parse_control_command,
         map(parse_expression, |expr| {
             Ok(Command::Expression(Box::new(expr)))
         }),
@@ -483,7 +1210,7 @@ fn parse_regular_command(input: &str) -> IResult<&str, Result<Command, RSLogoErr
 
 fn parse_command(input: &str) -> IResult<&str, Result<Command, RSLogoError>> {
     // First check if we have an END without a TO
-    if let Ok((remaining, _)) = tag::<&str, &str, nom::error::Error<&str>>("END")(input) {
+    if let Ok((remaining, _)) = whole_word("END")(input) {
         // Find the line number
         let line_num = input[..input.len() - remaining.len()]
             .chars()
@@ -501,52 +1228,94 @@ fn parse_command(input: &str) -> IResult<&str, Result<Command, RSLogoError>> {
         })));
     }
 
-    alt((parse_procedure_definition, parse_regular_command))(input)
+    let (remaining, command) = alt((parse_procedure_definition, parse_regular_command))(input)?;
+    Ok((
+        remaining,
+        command.map(|command| locate(input, remaining, command)),
+    ))
+}
+
+// Wrap a command in the span of source it was parsed from. Only the distance
+// from the end of the source is known here; parse_program turns it into an
+// offset from the start.
+fn locate(input: &str, remaining: &str, command: Command) -> Command {
+    let span = Span {
+        offset: input.len(),
+        len: input.len() - remaining.len(),
+    };
+    Command::Located(span, Box::new(command))
+}
+
+fn resolve_spans(commands: &mut [Command], source_len: usize) {
+    for command in commands {
+        if let Command::Located(span, _) = command {
+            span.offset = source_len - span.offset;
+        }
+        for block in command.blocks_mut() {
+            resolve_spans(block, source_len);
+        }
+    }
 }
 
+// A // comment runs to the end of the line. A /* */ comment can span lines
+// and ends at the first */; they don't nest.
 fn parse_comment(input: &str) -> IResult<&str, ()> {
-    value((), tuple((tag("//"), not_line_ending, opt(line_ending))))(input)
+    alt((
+        value((), tuple((tag("//"), not_line_ending, opt(line_ending)))),
+        value((), tuple((tag("/*"), take_until("*/"), tag("*/")))),
+    ))(input)
+}
+
+// Whitespace, newlines and comments. Comments count as whitespace, so they
+// can go anywhere a space can: between commands, after a command on the
+// same line, or between a command and its arguments.
+fn blank(input: &str) -> IResult<&str, ()> {
+    value((), many0(alt((value((), multispace1), parse_comment))))(input)
+}
+
+// The same, but at least one space, newline or comment, wherever one token
+// has to be kept apart from the next
+fn blank1(input: &str) -> IResult<&str, &str> {
+    recognize(many1(alt((multispace1, recognize(parse_comment)))))(input)
 }
 
 pub fn parse_program(input: &str) -> Result<Program, RSLogoError> {
-    println!("Parsing input: '{}'", input);
+    trace!("Parsing input: '{}'", input);
 
     if input.trim().is_empty() {
-        println!("Input is empty, returning empty program");
+        trace!("Input is empty, returning empty program");
         return Ok(Program {
             commands: Vec::new(),
+            source: input.to_string(),
         });
     }
 
-    let parse_result: IResult<&str, Vec<Option<Result<Command, RSLogoError>>>> =
-        all_consuming(many0(terminated(
-            alt((
-                map(parse_comment, |_| None),
-                map(delimited(multispace0, parse_command, multispace0), Some),
-                map(line_ending, |_| None),
-            )),
-            many0(line_ending),
-        )))(input);
+    let parse_result: IResult<&str, Vec<Result<Command, RSLogoError>>> =
+        all_consuming(preceded(blank, many0(terminated(parse_command, blank))))(input);
 
     match parse_result.finish() {
         Ok((_, commands)) => {
             let filtered_commands: Result<Vec<Command>, RSLogoError> =
-                commands.into_iter().flatten().collect();
+                commands.into_iter().collect();
 
             match filtered_commands {
-                Ok(cmds) => {
-                    println!("Successfully parsed {} commands", cmds.len());
-                    println!("Commands: {:?}", cmds);
-                    Ok(Program { commands: cmds })
+                Ok(mut cmds) => {
+                    trace!("Successfully parsed {} commands", cmds.len());
+                    trace!("Commands: {:?}", cmds);
+                    resolve_spans(&mut cmds, input.len());
+                    Ok(Program {
+                        commands: cmds,
+                        source: input.to_string(),
+                    })
                 }
                 Err(e) => {
-                    println!("Error collecting commands: {:?}", e);
-                    Err(e)
+                    trace!("Error collecting commands: {:?}", e);
+                    Err(in_source(e, input))
                 }
             }
         }
         Err(e) => {
-            println!("Parse error: {:?}", e);
+            trace!("Parse error: {:?}", e);
             Err(RSLogoError::ParseError {
                 input: input.to_string(),
                 span: (
@@ -558,3 +1327,91 @@ pub fn parse_program(input: &str) -> Result<Program, RSLogoError> {
         }
     }
 }
+
+// A command's parser only sees the source from that command on, so a parse
+// error it reports is relative to that. Point it into the whole source.
+fn in_source(err: RSLogoError, source: &str) -> RSLogoError {
+    match err {
+        RSLogoError::ParseError {
+            input,
+            span,
+            message,
+        } if source.ends_with(input.as_str()) => RSLogoError::ParseError {
+            span: (span.0 + source.len() - input.len(), span.1),
+            input: source.to_string(),
+            message,
+        },
+        err => err,
+    }
+}
+
+// Parse a program and splice in the commands of every file it INCLUDEs.
+// Paths are relative to the including file, or to the working directory
+// when the program has no file of its own (e.g. it came from stdin).
+pub fn parse_program_with_includes(
+    input: &str,
+    file_path: Option<&Path>,
+) -> Result<Program, RSLogoError> {
+    let mut program = parse_program(input)?;
+    let mut chain = Vec::new();
+    let base_dir = match file_path {
+        Some(path) => {
+            let canonical = fs::canonicalize(path)?;
+            let base_dir = canonical.parent().map(Path::to_path_buf);
+            chain.push(canonical);
+            base_dir.unwrap_or_default()
+        }
+        None => PathBuf::from("."),
+    };
+    program.commands = expand_includes(program.commands, &base_dir, &mut chain)?;
+    Ok(program)
+}
+
+// `chain` holds the files currently being included, outermost first, so a
+// file that appears twice in it includes itself
+fn expand_includes(
+    commands: Vec<Command>,
+    base_dir: &Path,
+    chain: &mut Vec<PathBuf>,
+) -> Result<Vec<Command>, RSLogoError> {
+    let mut expanded = Vec::new();
+    for command in commands {
+        let path = match command.unlocated() {
+            Command::Include(path) => fs::canonicalize(base_dir.join(path))?,
+            _ => {
+                expanded.push(command);
+                continue;
+            }
+        };
+        if chain.contains(&path) {
+            let mut cycle: Vec<String> = chain.iter().map(|p| p.display().to_string()).collect();
+            cycle.push(path.display().to_string());
+            return Err(RSLogoError::IncludeCycle { chain: cycle });
+        }
+
+        trace!("Including {:?}", path);
+        let source = fs::read_to_string(&path)?;
+        let mut included = parse_program(&source)?;
+        // Spans in an included file point into its own source, not the
+        // program being reported on; errors fall back to the calling command
+        strip_locations(&mut included.commands);
+
+        let included_dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+        chain.push(path);
+        expanded.extend(expand_includes(included.commands, &included_dir, chain)?);
+        chain.pop();
+    }
+    Ok(expanded)
+}
+
+fn strip_locations(commands: &mut [Command]) {
+    for command in commands.iter_mut() {
+        if let Command::Located(_, inner) = command {
+            let inner = std::mem::replace(inner.as_mut(), Command::Stop);
+            *command = inner;
+        }
+        for block in command.blocks_mut() {
+            strip_locations(block);
+        }
+    }
+}
@@ -0,0 +1,30 @@
+use unsvg::Color;
+
+// One straight stroke the pen drew, in canvas pixels before --scale. A
+// thick pen still records a single segment along the middle of the stroke.
+pub struct Segment {
+    pub from: (i32, i32),
+    pub to: (i32, i32),
+    pub color: Color,
+}
+
+// A JSON array with one {"from", "to", "color"} object per segment, in the
+// order they were drawn
+pub fn to_json(segments: &[Segment]) -> String {
+    let items: Vec<String> = segments
+        .iter()
+        .map(|segment| {
+            format!(
+                "{{\"from\":[{},{}],\"to\":[{},{}],\"color\":\"#{:02X}{:02X}{:02X}\"}}",
+                segment.from.0,
+                segment.from.1,
+                segment.to.0,
+                segment.to.1,
+                segment.color.red,
+                segment.color.green,
+                segment.color.blue
+            )
+        })
+        .collect();
+    format!("[{}]\n", items.join(",\n"))
+}
@@ -1,38 +1,76 @@
-use crate::rs_ast::{Command, Value};
+use crate::rs_ast::{Block, Command, Expression, Value};
 use crate::rs_error::RSLogoError;
 use crate::rs_variables::VariableManager;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::rc::Rc;
 
+// Parameters and body are shared so a call can hold on to them while the
+// interpreter is mutably borrowed, without copying the whole body each time
 #[derive(Debug, Clone)]
 pub struct Procedure {
     name: String,
-    parameters: Vec<String>,
-    body: Vec<Command>,
+    parameters: Rc<Vec<String>>,
+    rest: Option<String>,
+    body: Block,
 }
 
 #[derive(Debug)]
 pub struct ProcedureManager {
     procedures: HashMap<String, Procedure>,
     parameter_stack: Vec<HashMap<String, Value>>, // Stack of parameter bindings for each call
+    depth: usize,
+    max_depth: usize,
+}
+
+pub const DEFAULT_MAX_DEPTH: usize = 1000;
+
+// How many arguments a procedure takes. A rest parameter lets it take any
+// number beyond the named ones.
+#[derive(Debug, Clone, Copy)]
+struct Arity {
+    required: usize,
+    variadic: bool,
+}
+
+impl Arity {
+    fn accepts(self, got: usize) -> bool {
+        got == self.required || (self.variadic && got > self.required)
+    }
+}
+
+impl fmt::Display for Arity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.variadic {
+            write!(f, "at least {} arguments", self.required)
+        } else {
+            write!(f, "{} arguments", self.required)
+        }
+    }
 }
 
 impl Procedure {
-    pub fn new(name: String, parameters: Vec<String>, body: Vec<Command>) -> Self {
+    pub fn new(name: String, parameters: Vec<String>, rest: Option<String>, body: Block) -> Self {
         Self {
             name,
-            parameters,
+            parameters: Rc::new(parameters),
+            rest,
             body,
         }
     }
 
-    pub fn get_body(&self) -> &Vec<Command> {
-        println!("get procedure \"{}\" body", self.name);
-        &self.body
+    pub fn get_body(&self) -> Block {
+        trace!("get procedure \"{}\" body", self.name);
+        Rc::clone(&self.body)
     }
 
-    pub fn get_parameters(&self) -> &Vec<String> {
-        println!("get procedure \"{}\" parameter", self.name);
-        &self.parameters
+    pub fn get_parameters(&self) -> Rc<Vec<String>> {
+        trace!("get procedure \"{}\" parameter", self.name);
+        Rc::clone(&self.parameters)
+    }
+
+    pub fn get_rest(&self) -> Option<String> {
+        self.rest.clone()
     }
 }
 
@@ -41,9 +79,22 @@ impl ProcedureManager {
         Self {
             procedures: HashMap::new(),
             parameter_stack: Vec::new(),
+            depth: 0,
+            max_depth: DEFAULT_MAX_DEPTH,
         }
     }
 
+    pub fn set_max_depth(&mut self, max_depth: usize) {
+        self.max_depth = max_depth;
+    }
+
+    // Forget every procedure and call, keeping the depth limit
+    pub fn clear(&mut self) {
+        self.procedures.clear();
+        self.parameter_stack.clear();
+        self.depth = 0;
+    }
+
     // Define a procedure, evaluating parameter names at definition time
     // Ensuring definition-time evaluation of procedure arguments
     // Definition-time binding: Capturing arguments during procedure definition
@@ -51,7 +102,8 @@ impl ProcedureManager {
         &mut self,
         name: String,
         parameters: Vec<String>,
-        body: Vec<Command>,
+        rest: Option<String>,
+        body: Block,
         variables: &mut VariableManager,
     ) -> Result<(), RSLogoError> {
         // First evaluate parameter names using current variable values
@@ -73,39 +125,79 @@ impl ProcedureManager {
         }
 
         // Store procedure with evaluated parameter names
-        let procedure = Procedure::new(name.clone(), evaluated_params, body);
-        self.procedures.insert(name, procedure);
+        let procedure = Procedure::new(name.clone(), evaluated_params, rest, body);
+        if self.procedures.insert(name.clone(), procedure).is_some() {
+            trace!("Procedure \"{}\" redefined", name);
+        }
         Ok(())
     }
 
+    // Remove a procedure. Calls already running keep their own copy of the body.
+    pub fn erase_procedure(&mut self, name: &str) -> Result<(), RSLogoError> {
+        match self.procedures.remove(name) {
+            Some(_) => Ok(()),
+            None => Err(RSLogoError::InvalidArgument {
+                command: "ERASE".to_string(),
+                argument: name.to_string(),
+                expected: "a defined procedure name".to_string(),
+            }),
+        }
+    }
+
     pub fn get_procedure(&self, name: &str) -> Option<&Procedure> {
         self.procedures.get(name)
     }
 
-    // Push new parameter bindings for a procedure call
+    // Push new parameter bindings for a procedure call. With a rest
+    // parameter, every argument after the named ones goes into its list.
     pub fn push_parameters(
         &mut self,
+        name: &str,
         params: &[String],
-        args: Vec<Value>,
+        rest: Option<&str>,
+        mut args: Vec<Value>,
     ) -> Result<(), RSLogoError> {
-        if params.len() != args.len() {
+        // Fail cleanly before runaway recursion can exhaust the native stack
+        if self.depth >= self.max_depth {
+            return Err(RSLogoError::RecursionLimitExceeded {
+                procedure: name.to_string(),
+                limit: self.max_depth,
+            });
+        }
+
+        let arity = Arity {
+            required: params.len(),
+            variadic: rest.is_some(),
+        };
+        if !arity.accepts(args.len()) {
             return Err(RSLogoError::InvalidArgument {
                 command: "procedure call".to_string(),
                 argument: format!("{} arguments", args.len()),
-                expected: format!("{} arguments", params.len()),
+                expected: arity.to_string(),
             });
         }
 
         let mut param_bindings = HashMap::new();
+        if let Some(rest) = rest {
+            let extra = args.split_off(params.len());
+            param_bindings.insert(rest.to_string(), Value::List(extra));
+        }
         for (param, arg) in params.iter().zip(args) {
             param_bindings.insert(param.clone(), arg);
         }
         self.parameter_stack.push(param_bindings);
+        self.depth += 1;
         Ok(())
     }
 
     pub fn pop_parameters(&mut self) {
         self.parameter_stack.pop();
+        self.depth = self.depth.saturating_sub(1);
+    }
+
+    // Whether a procedure call is currently executing
+    pub fn in_procedure(&self) -> bool {
+        !self.parameter_stack.is_empty()
     }
 
     // Get current value of a parameter
@@ -119,3 +211,228 @@ impl ProcedureManager {
         None
     }
 }
+
+// Check every procedure call against the procedures defined anywhere in the
+// program, before anything runs. Procedures may be defined after they are
+// called, so this has to look at the whole program rather than happen in nom.
+pub fn check_arity(commands: &[Command]) -> Result<(), RSLogoError> {
+    let mut arities: HashMap<String, Vec<Arity>> = HashMap::new();
+    collect_arities(commands, &mut arities);
+    let mut problems = Vec::new();
+    check_calls(commands, &arities, "top level", false, &mut problems);
+    match problems.into_iter().next() {
+        Some(problem) => Err(problem),
+        None => Ok(()),
+    }
+}
+
+// Static checks for --check: every problem check_arity would stop at, plus
+// calls to undefined procedures and references to variables that nothing
+// in the program ever defines
+pub fn lint(commands: &[Command]) -> Vec<RSLogoError> {
+    let mut arities: HashMap<String, Vec<Arity>> = HashMap::new();
+    collect_arities(commands, &mut arities);
+    let mut problems = Vec::new();
+    check_calls(commands, &arities, "top level", true, &mut problems);
+
+    let mut defined = HashSet::new();
+    // A MAKE with a computed name could define anything, so variables
+    // can't be checked statically
+    if collect_variables(commands, &mut defined) {
+        let mut names: Vec<String> = defined.iter().cloned().collect();
+        names.sort();
+        check_variables(commands, &defined, &names, &mut problems);
+    }
+    problems
+}
+
+// For --list-procedures: one line per procedure the program defines, in
+// source order, giving its parameters and how many commands its body has
+pub fn describe_procedures(commands: &[Command]) -> Vec<String> {
+    let mut descriptions = Vec::new();
+    for command in commands {
+        if let Command::ProcedureDefinition {
+            name,
+            parameters,
+            rest,
+            body,
+        } = command.unlocated()
+        {
+            let mut signature = name.clone();
+            for param in parameters {
+                signature.push_str(&format!(" :{}", param));
+            }
+            if let Some(rest) = rest {
+                signature.push_str(&format!(" :{}...", rest));
+            }
+            let count = body.len();
+            descriptions.push(format!(
+                "{} ({} command{})",
+                signature,
+                count,
+                if count == 1 { "" } else { "s" }
+            ));
+        }
+        for block in command.blocks() {
+            descriptions.extend(describe_procedures(block));
+        }
+    }
+    descriptions
+}
+
+fn collect_arities(commands: &[Command], arities: &mut HashMap<String, Vec<Arity>>) {
+    for command in commands {
+        if let Command::ProcedureDefinition {
+            name,
+            parameters,
+            rest,
+            ..
+        } = command.unlocated()
+        {
+            arities.entry(name.clone()).or_default().push(Arity {
+                required: parameters.len(),
+                variadic: rest.is_some(),
+            });
+        }
+        for block in command.blocks() {
+            collect_arities(block, arities);
+        }
+    }
+}
+
+fn check_calls(
+    commands: &[Command],
+    arities: &HashMap<String, Vec<Arity>>,
+    location: &str,
+    report_undefined: bool,
+    problems: &mut Vec<RSLogoError>,
+) {
+    for command in commands {
+        // Calls to undefined procedures are otherwise left for the
+        // interpreter to report
+        let check = |name: &str, got: usize| match arities.get(name) {
+            Some(expected) if !expected.iter().any(|arity| arity.accepts(got)) => {
+                Some(RSLogoError::ArityMismatch {
+                    procedure: name.to_string(),
+                    expected: expected[0].to_string(),
+                    got,
+                    context: format!("'{}' at {}", command, location),
+                })
+            }
+            None if report_undefined => Some(RSLogoError::InvalidArgument {
+                command: "procedure call".to_string(),
+                argument: name.to_string(),
+                expected: "a defined procedure name".to_string(),
+            }),
+            _ => None,
+        };
+
+        let mut calls = Vec::new();
+        if let Command::ProcedureCall { name, arguments } = command.unlocated() {
+            calls.push((name, arguments.len()));
+        }
+        for expr in command.expressions() {
+            expr.visit(&mut |e| {
+                if let Expression::Call(name, arguments) = e {
+                    calls.push((name, arguments.len()));
+                }
+            });
+        }
+        for (name, got) in calls {
+            if let Some(problem) = check(name, got) {
+                problems.push(locate(command, problem));
+            }
+        }
+
+        let inner_location = match command.unlocated() {
+            Command::ProcedureDefinition { name, .. } => format!("procedure '{}'", name),
+            _ => location.to_string(),
+        };
+        for block in command.blocks() {
+            check_calls(block, arities, &inner_location, report_undefined, problems);
+        }
+    }
+}
+
+// Gather every variable name the program could define. Returns false if a
+// MAKE names its variable with anything other than a literal word.
+fn collect_variables(commands: &[Command], defined: &mut HashSet<String>) -> bool {
+    let mut all_literal = true;
+    for command in commands {
+        match command.unlocated() {
+            Command::Make(Expression::Value(Value::String(name)), _)
+            | Command::Local(name)
+            | Command::Global(name)
+            | Command::For { var: name, .. }
+            | Command::ForEach { var: name, .. } => {
+                defined.insert(name.clone());
+            }
+            Command::Make(_, _) => all_literal = false,
+            Command::ProcedureDefinition {
+                parameters, rest, ..
+            } => {
+                defined.extend(parameters.iter().cloned());
+                defined.extend(rest.iter().cloned());
+            }
+            _ => {}
+        }
+        for block in command.blocks() {
+            all_literal &= collect_variables(block, defined);
+        }
+    }
+    all_literal
+}
+
+fn check_variables(
+    commands: &[Command],
+    defined: &HashSet<String>,
+    names: &[String],
+    problems: &mut Vec<RSLogoError>,
+) {
+    for command in commands {
+        let mut used = Vec::new();
+        if let Command::AddAssign(name, _) = command.unlocated() {
+            used.push(name);
+        }
+        for expr in command.expressions() {
+            expr.visit(&mut |e| {
+                if let Expression::Value(value) = e {
+                    collect_references(value, &mut used);
+                }
+            });
+        }
+        for name in used {
+            if !defined.contains(name) {
+                let problem = RSLogoError::UndefinedVariable {
+                    variable_name: name.clone(),
+                    defined_variables: names.to_vec(),
+                };
+                problems.push(locate(command, problem));
+            }
+        }
+
+        for block in command.blocks() {
+            check_variables(block, defined, names, problems);
+        }
+    }
+}
+
+fn collect_references<'a>(value: &'a Value, used: &mut Vec<&'a String>) {
+    match value {
+        Value::Variable(name) => used.push(name),
+        Value::List(items) => {
+            for item in items {
+                collect_references(item, used);
+            }
+        }
+        _ => {}
+    }
+}
+
+// Point a problem at the command it was found in, when the parser recorded one
+fn locate(command: &Command, problem: RSLogoError) -> RSLogoError {
+    match command {
+        Command::Located(span, _) => problem.located(span.offset, span.len),
+        _ => problem,
+    }
+}
@@ -0,0 +1,97 @@
+use image::{Rgba, RgbaImage};
+use std::collections::{HashMap, HashSet};
+use unsvg::Color;
+
+// unsvg can't read pixels back, so lines drawn with PENREVERSE are kept
+// aside and XORed into raster images on the way out, on top of everything
+// drawn normally. SVG has no way to show them.
+pub struct ReverseStroke {
+    // Canvas pixels the stroke covers, before --scale, each listed once
+    pub pixels: Vec<(i32, i32)>,
+    pub color: Color,
+}
+
+// A straight line between two canvas pixels, both ends included
+pub type Line = ((i32, i32), (i32, i32));
+
+// Every pixel on the given lines that is on a width x height canvas, ends
+// included. A pixel shared by two lines of the same stroke (a thick pen) is
+// only listed once, so the stroke doesn't erase itself.
+pub fn stroke_pixels(lines: &[Line], width: u32, height: u32) -> Vec<(i32, i32)> {
+    let on_canvas =
+        |(x, y): (i32, i32)| x >= 0 && y >= 0 && (x as u32) < width && (y as u32) < height;
+    let mut seen = HashSet::new();
+    let mut pixels = Vec::new();
+    for &(from, to) in lines {
+        for pixel in line_pixels(from, to) {
+            if on_canvas(pixel) && seen.insert(pixel) {
+                pixels.push(pixel);
+            }
+        }
+    }
+    pixels
+}
+
+// XOR every stroke into the image. Strokes are combined first, so a pixel
+// covered twice by the same color ends up exactly as it was. Transparent
+// pixels count as black.
+pub fn apply(pixels: &mut RgbaImage, strokes: &[ReverseStroke], scale: u32) {
+    let mut mask: HashMap<(i32, i32), [u8; 3]> = HashMap::new();
+    for stroke in strokes {
+        for &pixel in &stroke.pixels {
+            let xor = mask.entry(pixel).or_insert([0, 0, 0]);
+            xor[0] ^= stroke.color.red;
+            xor[1] ^= stroke.color.green;
+            xor[2] ^= stroke.color.blue;
+        }
+    }
+
+    // Stroke pixels are all on the canvas, so each scale x scale block is
+    // inside the image
+    for ((x, y), xor) in mask {
+        if xor == [0, 0, 0] {
+            continue;
+        }
+        for py in y as u32 * scale..(y as u32 + 1) * scale {
+            for px in x as u32 * scale..(x as u32 + 1) * scale {
+                let pixel = pixels.get_pixel_mut(px, py);
+                *pixel = if pixel[3] == 0 {
+                    Rgba([xor[0], xor[1], xor[2], 255])
+                } else {
+                    Rgba([
+                        pixel[0] ^ xor[0],
+                        pixel[1] ^ xor[1],
+                        pixel[2] ^ xor[2],
+                        pixel[3],
+                    ])
+                };
+            }
+        }
+    }
+}
+
+// Bresenham's line, always walked from the same end so that drawing a line
+// backwards covers exactly the same pixels
+fn line_pixels(from: (i32, i32), to: (i32, i32)) -> Vec<(i32, i32)> {
+    let (from, to) = if from <= to { (from, to) } else { (to, from) };
+    let (mut x, mut y) = from;
+    let dx = (to.0 - x).abs();
+    let dy = -(to.1 - y).abs();
+    let step_x = if x < to.0 { 1 } else { -1 };
+    let step_y = if y < to.1 { 1 } else { -1 };
+    let mut error = dx + dy;
+    let mut pixels = vec![(x, y)];
+    while (x, y) != to {
+        let doubled = 2 * error;
+        if doubled >= dy {
+            error += dy;
+            x += step_x;
+        }
+        if doubled <= dx {
+            error += dx;
+            y += step_y;
+        }
+        pixels.push((x, y));
+    }
+    pixels
+}
@@ -1,28 +1,211 @@
+use crate::rs_animation::Animation;
 use crate::rs_error::RSLogoError;
-use std::path::Path;
+use crate::rs_group::Groups;
+use crate::rs_label::{self, Label, DEFAULT_FONT_SIZE};
+use crate::rs_path::{self, Segment};
+use crate::rs_reverse::{self, ReverseStroke};
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::{self, FilterType};
+use image::{DynamicImage, RgbaImage};
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
 use unsvg::{Color, Image, COLORS};
 
+pub const DEFAULT_JPEG_QUALITY: u8 = 90;
+
+// Names SETPENCOLOR accepts for the palette, in COLORS order
+pub const COLOR_NAMES: [&str; 16] = [
+    "BLACK", "BLUE", "CYAN", "GREEN", "RED", "MAGENTA", "YELLOW", "WHITE", "BROWN", "TAN",
+    "FOREST", "AQUA", "SALMON", "PURPLE", "ORANGE", "GREY",
+];
+
+// Names SETHEADING accepts, with their headings; 0 is up and headings
+// grow clockwise
+const CARDINAL_HEADINGS: [(&str, f64); 4] = [
+    ("NORTH", 0.0),
+    ("EAST", 90.0),
+    ("SOUTH", 180.0),
+    ("WEST", 270.0),
+];
+
+// unsvg fills the canvas black, so the pen starts white to show up on it;
+// --pen-color overrides it
+const DEFAULT_PEN_COLOR: Color = Color {
+    red: 255,
+    green: 255,
+    blue: 255,
+};
+
+// Drawn in a colour outside the palette so the cursor stands out from the path
+const CURSOR_COLOR: Color = Color {
+    red: 255,
+    green: 0,
+    blue: 255,
+};
+
+// What happens when a movement would leave the canvas
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EdgeMode {
+    // Stop at the border
+    Clamp,
+    // Reappear at the opposite border and carry on
+    Wrap,
+    // Keep going off-canvas; the line is clipped when drawn
+    Ignore,
+}
+
+// Where logical (0, 0) sits on the canvas. Whatever the origin, the turtle
+// starts in the middle of the canvas.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Origin {
+    TopLeft,
+    Center,
+    BottomLeft,
+}
+
+// Which way on screen YCOR grows. Headings are unaffected: 0 is always up.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum YAxis {
+    Down,
+    Up,
+}
+
+// Where the turtle is and how its pen is set, as XCOR, YCOR, HEADING,
+// PENDOWNP and COLOR would report them, for code using the library
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TurtleSnapshot {
+    pub x: i32,
+    pub y: i32,
+    pub heading: f64,
+    pub pen_down: bool,
+    // None when the pen was set with SETRGB rather than from the palette
+    pub color_index: Option<u32>,
+}
+
+// Everything PUSHTURTLE saves and POPTURTLE puts back
+#[derive(Clone, Copy)]
+pub struct TurtleState {
+    x: i32,
+    y: i32,
+    heading: f64,
+    pen_down: bool,
+    color: Color,
+    custom_color: bool,
+}
+
 pub struct Turtle {
     x: i32,
     y: i32,
-    heading: i32,
+    home_x: i32,
+    home_y: i32,
+    width: u32,
+    height: u32,
+    // Kept unrounded so fractional turns add up; lines are drawn at the
+    // nearest whole degree, since that is all unsvg can draw
+    heading: f64,
     pen_down: bool,
+    pen_size: u32,
+    // PENREVERSE: lines are XORed with what is under them instead of drawn
+    reverse_pen: bool,
+    reverse_strokes: Vec<ReverseStroke>,
     color: Color,
+    custom_color: bool,
+    // What SETPENCOLOR indexes into; unsvg's COLORS unless --palette is given
+    palette: Vec<Color>,
+    background: Option<Color>,
+    fill_points: Option<Vec<(i32, i32)>>,
+    saved_states: Vec<TurtleState>,
+    show_turtle: bool,
+    animation: Option<Animation>,
+    // Every stroke drawn, kept only when --export-path asks for it
+    segments: Option<Vec<Segment>>,
+    labels: Vec<Label>,
+    font_size: u32,
+    groups: Groups,
+    // Lines handed to unsvg since the image was last wiped, which is how
+    // groups know where they start and end
+    lines_drawn: usize,
+    jpeg_quality: u8,
+    scale: u32,
+    edge_mode: EdgeMode,
+    origin: Origin,
+    y_axis: YAxis,
     image: Image,
 }
 
 impl Turtle {
     pub fn new(width: u32, height: u32) -> Self {
+        let home_x = (width / 2) as i32;
+        let home_y = (height / 2) as i32;
         Self {
-            x: (width / 2) as i32,
-            y: (height / 2) as i32,
-            heading: 0,
+            x: home_x,
+            y: home_y,
+            home_x,
+            home_y,
+            width,
+            height,
+            heading: 0.0,
             pen_down: false,
-            color: Color::white(),
+            pen_size: 1,
+            reverse_pen: false,
+            reverse_strokes: Vec::new(),
+            color: DEFAULT_PEN_COLOR,
+            custom_color: false,
+            palette: default_palette(),
+            background: None,
+            fill_points: None,
+            saved_states: Vec::new(),
+            show_turtle: false,
+            animation: None,
+            segments: None,
+            labels: Vec::new(),
+            font_size: DEFAULT_FONT_SIZE,
+            groups: Groups::new(),
+            lines_drawn: 0,
+            jpeg_quality: DEFAULT_JPEG_QUALITY,
+            scale: 1,
+            edge_mode: EdgeMode::Ignore,
+            origin: Origin::TopLeft,
+            y_axis: YAxis::Down,
             image: Image::new(width, height),
         }
     }
 
+    // Start recording frames for GIF output, keeping at most max_frames
+    pub fn enable_animation(&mut self, max_frames: usize) {
+        self.animation = Some(Animation::new(max_frames));
+    }
+
+    // Start keeping every drawn segment for save_path
+    pub fn enable_path_recording(&mut self) {
+        self.segments = Some(Vec::new());
+    }
+
+    // Turtle coordinates and the drawing stay in the logical canvas; only
+    // the output is enlarged, so this can be called at any time
+    pub fn set_scale(&mut self, scale: u32) -> Result<(), RSLogoError> {
+        let scale = scale.max(1);
+        scaled_size(self.width, self.height, scale)?;
+        self.scale = scale;
+        Ok(())
+    }
+
+    pub fn set_edge_mode(&mut self, mode: EdgeMode) {
+        self.edge_mode = mode;
+    }
+
+    // XCOR, YCOR, POS, SETX, SETY, SETPOS and TOWARDS work in these coordinates; the
+    // turtle itself keeps track of canvas pixels
+    pub fn set_coordinate_system(&mut self, origin: Origin, y_axis: YAxis) {
+        self.origin = origin;
+        self.y_axis = y_axis;
+    }
+
+    pub fn set_jpeg_quality(&mut self, quality: u8) {
+        self.jpeg_quality = quality;
+    }
+
     pub fn pen_up(&mut self) {
         self.pen_down = false;
     }
@@ -31,76 +214,388 @@ impl Turtle {
         self.pen_down = true;
     }
 
-    pub fn forward(&mut self, numpixels: i32) -> Result<(), RSLogoError> {
+    pub fn toggle_pen_reverse(&mut self) {
+        self.reverse_pen = !self.reverse_pen;
+    }
+
+    pub fn forward(&mut self, numpixels: f64) -> Result<(), RSLogoError> {
         let forward_heading = self.heading;
-        if numpixels < 0 {
+        if numpixels < 0.0 {
             return self.back(-numpixels);
         }
         self.process_movement(numpixels, forward_heading)
     }
 
-    pub fn back(&mut self, numpixels: i32) -> Result<(), RSLogoError> {
-        let back_heading = self.heading + 180;
-        if numpixels < 0 {
+    pub fn back(&mut self, numpixels: f64) -> Result<(), RSLogoError> {
+        let back_heading = self.heading + 180.0;
+        if numpixels < 0.0 {
             return self.forward(-numpixels);
         }
         self.process_movement(numpixels, back_heading)
     }
 
-    pub fn left(&mut self, numpixels: i32) -> Result<(), RSLogoError> {
-        let left_heading = self.heading - 90;
-        if numpixels < 0 {
+    pub fn left(&mut self, numpixels: f64) -> Result<(), RSLogoError> {
+        let left_heading = self.heading - 90.0;
+        if numpixels < 0.0 {
             return self.right(-numpixels);
         }
         self.process_movement(numpixels, left_heading)
     }
 
-    pub fn right(&mut self, numpixels: i32) -> Result<(), RSLogoError> {
-        let right_heading = self.heading + 90;
-        if numpixels < 0 {
-            return self.left(numpixels);
+    pub fn right(&mut self, numpixels: f64) -> Result<(), RSLogoError> {
+        let right_heading = self.heading + 90.0;
+        if numpixels < 0.0 {
+            return self.left(-numpixels);
         }
         self.process_movement(numpixels, right_heading)
     }
 
-    pub fn set_pen_color(&mut self, colorcode: u32) -> Result<(), RSLogoError> {
-        if colorcode >= (COLORS.len() as u32) {
-            return Err(RSLogoError::InvalidArgument {
+    // Walk along a circle of the given radius, turning right for a positive
+    // angle and left for a negative one. The curve is approximated with one
+    // segment per degree, each aimed at the exact point on the circle so
+    // rounding doesn't accumulate.
+    pub fn arc(&mut self, angle: f64, radius: f64) -> Result<(), RSLogoError> {
+        let side = if angle < 0.0 { -90.0 } else { 90.0 };
+        let start_heading = self.heading;
+        let (offset_x, offset_y) = unit_vector(start_heading + side);
+        let centre_x = self.x as f64 + radius * offset_x;
+        let centre_y = self.y as f64 + radius * offset_y;
+
+        let steps = angle.abs().ceil() as i32;
+        for step in 1..=steps {
+            let turned = angle * step as f64 / steps as f64;
+            let (to_x, to_y) = unit_vector(start_heading + turned - side);
+            let target = (
+                (centre_x + radius * to_x).round() as i32,
+                (centre_y + radius * to_y).round() as i32,
+            );
+            if target == (self.x, self.y) {
+                continue;
+            }
+
+            self.line_to(target)?;
+        }
+
+        self.turn(angle);
+        self.capture_frame();
+        Ok(())
+    }
+
+    // Any palette index is accepted, however many colors the palette holds
+    pub fn set_pen_color(&mut self, colorcode: i32) -> Result<(), RSLogoError> {
+        let color = usize::try_from(colorcode)
+            .ok()
+            .and_then(|index| self.palette.get(index))
+            .ok_or_else(|| RSLogoError::InvalidArgument {
                 command: "SETPENCOLOR".to_string(),
                 argument: colorcode.to_string(),
-                expected: "SETPENCOLOR <u32 type String>".to_string(),
-            });
-        }
-        self.color = COLORS[colorcode as usize];
+                expected: palette_index_expected(&self.palette),
+            })?;
+        self.color = *color;
+        self.custom_color = false;
+        Ok(())
+    }
+
+    pub fn set_pen_color_name(&mut self, name: &str) -> Result<(), RSLogoError> {
+        let index = COLOR_NAMES
+            .iter()
+            .position(|known| known.eq_ignore_ascii_case(name))
+            .ok_or_else(|| RSLogoError::InvalidArgument {
+                command: "SETPENCOLOR".to_string(),
+                argument: name.to_string(),
+                expected: format!(
+                    "{} or one of {}",
+                    palette_index_expected(&self.palette),
+                    COLOR_NAMES.join(", ")
+                ),
+            })?;
+        self.set_pen_color(index as i32)
+    }
+
+    // A #RRGGBB or #RGB color, which like SETRGB has no palette index
+    pub fn set_pen_color_hex(&mut self, spec: &str) -> Result<(), RSLogoError> {
+        let color = parse_hex_color(spec).ok_or_else(|| RSLogoError::InvalidArgument {
+            command: "SETPENCOLOR".to_string(),
+            argument: spec.to_string(),
+            expected: "a #RRGGBB or #RGB hex color".to_string(),
+        })?;
+        self.set_rgb(color.red, color.green, color.blue);
         Ok(())
     }
 
-    pub fn turn(&mut self, degrees: i32) {
-        self.heading += degrees;
+    // Replace the palette. A pen color the new palette doesn't have is kept,
+    // but COLOR reports it as custom from then on.
+    pub fn set_palette(&mut self, palette: Vec<Color>) {
+        if !palette.contains(&self.color) {
+            self.custom_color = true;
+        }
+        self.palette = palette;
+    }
+
+    pub fn palette_len(&self) -> usize {
+        self.palette.len()
     }
 
-    pub fn set_heading(&mut self, degrees: i32) {
-        self.heading = degrees;
+    pub fn set_rgb(&mut self, red: u8, green: u8, blue: u8) {
+        self.color = Color { red, green, blue };
+        self.custom_color = true;
     }
 
-    pub fn set_x(&mut self, location: i32) {
+    // Any color, with COLOR reporting its index if the palette has it
+    pub fn set_color(&mut self, color: Color) {
+        self.color = color;
+        self.custom_color = !self.palette.contains(&color);
+    }
+
+    pub fn set_pen_size(&mut self, size: u32) {
+        self.pen_size = size;
+    }
+
+    // Start recording the turtle's path as the outline of a filled shape
+    pub fn begin_fill(&mut self) {
+        self.fill_points = Some(vec![(self.x, self.y)]);
+    }
+
+    pub fn end_fill(&mut self) -> Result<(), RSLogoError> {
+        let points = self
+            .fill_points
+            .take()
+            .ok_or_else(|| RSLogoError::MisplacedCommand {
+                command: "ENDFILL".to_string(),
+                context: "after a matching BEGINFILL".to_string(),
+            })?;
+        if points.len() < 3 {
+            eprintln!(
+                "Warning: ENDFILL with only {} point(s) recorded, nothing to fill",
+                points.len()
+            );
+            return Ok(());
+        }
+        self.fill_polygon(&points)
+    }
+
+    // Headings are kept in [0, 360) so the HEADING query stays comparable
+    pub fn turn(&mut self, degrees: f64) {
+        self.heading = normalize_heading(self.heading + normalize_heading(degrees));
+    }
+
+    pub fn set_heading(&mut self, degrees: f64) {
+        self.heading = normalize_heading(degrees);
+    }
+
+    pub fn set_heading_name(&mut self, name: &str) -> Result<(), RSLogoError> {
+        let (_, degrees) = CARDINAL_HEADINGS
+            .iter()
+            .find(|(known, _)| known.eq_ignore_ascii_case(name))
+            .ok_or_else(|| RSLogoError::InvalidArgument {
+                command: "SETHEADING".to_string(),
+                argument: name.to_string(),
+                expected: format!(
+                    "a number of degrees or one of {}",
+                    CARDINAL_HEADINGS.map(|(known, _)| known).join(", ")
+                ),
+            })?;
+        self.set_heading(*degrees);
+        Ok(())
+    }
+
+    fn set_x(&mut self, location: i32) {
         self.x = location;
     }
 
-    pub fn set_y(&mut self, location: i32) {
+    fn set_y(&mut self, location: i32) {
         self.y = location;
     }
 
-    pub fn save_image(&self, image_path: &Path) -> Result<(), RSLogoError> {
+    // SETX and SETY draw on the way when the pen is down, like FORWARD;
+    // JUMPX and JUMPY never do. All four take logical coordinates.
+    pub fn move_x(&mut self, location: i32) -> Result<(), RSLogoError> {
+        self.line_to((self.pixel_x(location), self.y))?;
+        self.capture_frame();
+        Ok(())
+    }
+
+    pub fn move_y(&mut self, location: i32) -> Result<(), RSLogoError> {
+        self.line_to((self.x, self.pixel_y(location)))?;
+        self.capture_frame();
+        Ok(())
+    }
+
+    // SETPOS: both coordinates at once, drawing when the pen is down
+    pub fn move_to(&mut self, x: i32, y: i32) -> Result<(), RSLogoError> {
+        self.line_to((self.pixel_x(x), self.pixel_y(y)))?;
+        self.capture_frame();
+        Ok(())
+    }
+
+    // An offset in logical coordinates, so a positive dy goes up when the
+    // y axis does. Draws when the pen is down; the heading is left alone.
+    pub fn move_by(&mut self, dx: i32, dy: i32) -> Result<(), RSLogoError> {
+        let target = (
+            self.x.saturating_add(dx),
+            self.y.saturating_add(dy.saturating_mul(self.y_sign())),
+        );
+        self.line_to(target)?;
+        self.capture_frame();
+        Ok(())
+    }
+
+    pub fn jump_x(&mut self, location: i32) {
+        self.set_x(self.pixel_x(location));
+    }
+
+    pub fn jump_y(&mut self, location: i32) {
+        self.set_y(self.pixel_y(location));
+    }
+
+    // Return to the starting point facing up, without drawing
+    pub fn home(&mut self) {
+        self.set_x(self.home_x);
+        self.set_y(self.home_y);
+        self.set_heading(0.0);
+    }
+
+    // Wipe everything drawn so far and send the turtle home
+    pub fn clear_screen(&mut self) -> Result<(), RSLogoError> {
+        self.image = self.blank_image();
+        self.lines_drawn = 0;
+        self.groups.clear();
+        self.labels.clear();
+        self.reverse_strokes.clear();
+        if let Some(segments) = self.segments.as_mut() {
+            segments.clear();
+        }
+        if let Some(animation) = self.animation.as_mut() {
+            animation.record_clear();
+        }
+        self.home();
+        self.paint_background()
+    }
+
+    // Back to the turtle Turtle::new makes, on a blank canvas. How the output
+    // was set up stays: palette, background, scale, edge mode, coordinate
+    // system, JPEG quality, and whether an animation or path is recorded,
+    // though both start again empty.
+    pub fn reset(&mut self) -> Result<(), RSLogoError> {
+        let mut fresh = Turtle::new(self.width, self.height);
+        fresh.set_palette(std::mem::take(&mut self.palette));
+        fresh.background = self.background;
+        fresh.scale = self.scale;
+        fresh.edge_mode = self.edge_mode;
+        fresh.origin = self.origin;
+        fresh.y_axis = self.y_axis;
+        fresh.jpeg_quality = self.jpeg_quality;
+        fresh.animation = self.animation.as_ref().map(Animation::restart);
+        fresh.segments = self.segments.as_ref().map(|_| Vec::new());
+        *self = fresh;
+        self.paint_background()
+    }
+
+    pub fn set_background(&mut self, color: Color) -> Result<(), RSLogoError> {
+        self.background = Some(color);
+        self.paint_background()
+    }
+
+    pub fn push_state(&mut self) {
+        self.saved_states.push(TurtleState {
+            x: self.x,
+            y: self.y,
+            heading: self.heading,
+            pen_down: self.pen_down,
+            color: self.color,
+            custom_color: self.custom_color,
+        });
+    }
+
+    // Restoring a position jumps there without drawing
+    pub fn pop_state(&mut self) -> Result<(), RSLogoError> {
+        let state = self
+            .saved_states
+            .pop()
+            .ok_or_else(|| RSLogoError::MisplacedCommand {
+                command: "POPTURTLE".to_string(),
+                context: "after a matching PUSHTURTLE".to_string(),
+            })?;
+        self.x = state.x;
+        self.y = state.y;
+        self.heading = state.heading;
+        self.pen_down = state.pen_down;
+        self.color = state.color;
+        self.custom_color = state.custom_color;
+        Ok(())
+    }
+
+    pub fn set_show_turtle(&mut self, show: bool) {
+        self.show_turtle = show;
+    }
+
+    // Write text at the turtle's position in the pen color
+    pub fn label(&mut self, text: String) {
+        self.labels.push(Label {
+            x: self.x,
+            y: self.y,
+            text,
+            color: self.color,
+            font_size: self.font_size,
+        });
+    }
+
+    // Lines drawn until the matching end_group go into an SVG <g> with this id
+    pub fn begin_group(&mut self, name: String) {
+        self.groups.begin(name, self.lines_drawn);
+    }
+
+    pub fn end_group(&mut self) -> Result<(), RSLogoError> {
+        if self.groups.end(self.lines_drawn) {
+            Ok(())
+        } else {
+            Err(RSLogoError::MisplacedCommand {
+                command: "ENDGROUP".to_string(),
+                context: "after a matching GROUP".to_string(),
+            })
+        }
+    }
+
+    // Applies to labels written from now on
+    pub fn set_font_size(&mut self, size: u32) {
+        self.font_size = size;
+    }
+
+    // Write the recorded segments as JSON; nothing is recorded unless
+    // enable_path_recording was called first
+    pub fn save_path(&self, path: &Path) -> Result<(), RSLogoError> {
+        let segments = self.segments.as_deref().unwrap_or(&[]);
+        fs::write(path, rs_path::to_json(segments))?;
+        Ok(())
+    }
+
+    pub fn save_image(&mut self, image_path: &Path) -> Result<(), RSLogoError> {
         match image_path.extension().and_then(|s| s.to_str()) {
             Some("svg") => {
-                self.image
-                    .save_svg(image_path)
-                    .map_err(|e| RSLogoError::ImageSaveError(e.to_string()))?;
+                fs::write(image_path, self.render_svg()?)?;
             }
             Some("png") => {
-                self.image
-                    .save_png(image_path)
+                self.rasterize_with_labels()?
+                    .save(image_path)
+                    .map_err(|e| RSLogoError::ImageSaveError(e.to_string()))?;
+            }
+            Some("gif") => {
+                // Without --animate the GIF is just the finished drawing
+                let still = Animation::new(0);
+                self.animation.as_ref().unwrap_or(&still).save_gif(
+                    self.rasterize_with_labels()?,
+                    self.width,
+                    self.height,
+                    self.scale,
+                    image_path,
+                )?;
+            }
+            Some("jpg") | Some("jpeg") => {
+                // JPEG has no alpha channel, so transparent pixels come out black
+                let pixels = DynamicImage::ImageRgba8(self.rasterize_with_labels()?).to_rgb8();
+                let mut file = File::create(image_path)?;
+                JpegEncoder::new_with_quality(&mut file, self.jpeg_quality)
+                    .encode_image(&pixels)
                     .map_err(|e| RSLogoError::ImageSaveError(e.to_string()))?;
             }
             _ => {
@@ -112,33 +607,646 @@ impl Turtle {
         Ok(())
     }
 
+    pub fn to_svg_string(&self) -> Result<String, RSLogoError> {
+        self.render_svg()
+    }
+
+    // unsvg can only write SVGs to a path, so render into a temporary file
+    // and read it back
+    fn render_svg(&self) -> Result<String, RSLogoError> {
+        if !self.reverse_strokes.is_empty() {
+            return Err(RSLogoError::ImageSaveError(
+                "PENREVERSE lines can't be drawn in an SVG; save a .png, .gif or .jpg instead"
+                    .to_string(),
+            ));
+        }
+        let temp_path = temp_file_path("svg");
+        self.output_image()?
+            .save_svg(&temp_path)
+            .map_err(|e| RSLogoError::ImageSaveError(e.to_string()))?;
+        let svg = fs::read_to_string(&temp_path);
+        // Clean up even if reading failed; a leftover temp file is harmless
+        let _ = fs::remove_file(&temp_path);
+        let svg = self.groups.add_to_svg(&svg?);
+        let svg = rs_label::add_to_svg(&svg, &self.labels);
+        Ok(self.enlarge_svg(&svg))
+    }
+
+    // The finished image as pixels, PENREVERSE lines and labels included
+    fn rasterize_with_labels(&self) -> Result<RgbaImage, RSLogoError> {
+        let mut pixels = enlarge(rasterize(&self.output_image()?)?, self.scale);
+        rs_reverse::apply(&mut pixels, &self.reverse_strokes, self.scale);
+        rs_label::draw_on_pixels(&mut pixels, &self.labels, self.scale);
+        Ok(pixels)
+    }
+
+    // --scale sets the SVG's size and leaves its viewBox on the logical
+    // canvas, so viewers draw everything that much bigger
+    fn enlarge_svg(&self, svg: &str) -> String {
+        if self.scale == 1 {
+            return svg.to_string();
+        }
+        // set_scale made sure these fit
+        svg.replacen(
+            &format!("width=\"{}\" height=\"{}\"", self.width, self.height),
+            &format!(
+                "width=\"{}\" height=\"{}\"",
+                self.width * self.scale,
+                self.height * self.scale
+            ),
+            1,
+        )
+    }
+
+    // Whether the turtle is outside the canvas, whatever the edge mode
+    pub fn is_offscreen(&self) -> bool {
+        self.x < 0 || self.y < 0 || self.x >= self.width as i32 || self.y >= self.height as i32
+    }
+
     pub fn get_x(&self) -> i32 {
-        self.x
+        self.x - self.origin_pixel().0
     }
 
     pub fn get_y(&self) -> i32 {
-        self.y
+        (self.y - self.origin_pixel().1) * self.y_sign()
+    }
+
+    // The heading that would point the turtle at a logical position
+    pub fn heading_towards(&self, x: f64, y: f64) -> f64 {
+        let (origin_x, origin_y) = self.origin_pixel();
+        let dx = origin_x as f64 + x - self.x as f64;
+        let dy = origin_y as f64 + y * self.y_sign() as f64 - self.y as f64;
+        if dx == 0.0 && dy == 0.0 {
+            // Already there; keep 0 rather than atan2's answer for -0.0
+            return 0.0;
+        }
+        dx.atan2(-dy).to_degrees().rem_euclid(360.0)
+    }
+
+    // The logical canvas size given on the command line, before --scale
+    pub fn get_width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn get_height(&self) -> u32 {
+        self.height
     }
 
-    pub fn get_heading(&self) -> i32 {
+    pub fn get_heading(&self) -> f64 {
         self.heading
     }
 
-    pub fn get_pen_color(&self) -> u32 {
-        COLORS.iter().position(|&c| c == self.color).unwrap_or(8) as u32
+    // None when the pen was set with SETRGB rather than from the palette
+    pub fn get_pen_color(&self) -> Option<u32> {
+        if self.custom_color {
+            return None;
+        }
+        Some(
+            self.palette
+                .iter()
+                .position(|&c| c == self.color)
+                .unwrap_or(8) as u32,
+        )
+    }
+
+    // The name SETPENCOLOR takes for the pen's palette index, e.g. "red"
+    // for index 4, whatever color --palette put there. SETRGB colors have
+    // no index and come back as #RRGGBB.
+    pub fn get_color_name(&self) -> String {
+        let named = self
+            .get_pen_color()
+            .and_then(|index| COLOR_NAMES.get(index as usize));
+        match named {
+            Some(name) => name.to_lowercase(),
+            None => format!(
+                "#{:02X}{:02X}{:02X}",
+                self.color.red, self.color.green, self.color.blue
+            ),
+        }
+    }
+
+    pub fn get_color(&self) -> Color {
+        self.color
+    }
+
+    pub fn is_pen_down(&self) -> bool {
+        self.pen_down
+    }
+
+    pub fn get_pen_size(&self) -> u32 {
+        self.pen_size
+    }
+
+    pub fn snapshot(&self) -> TurtleSnapshot {
+        TurtleSnapshot {
+            x: self.get_x(),
+            y: self.get_y(),
+            heading: self.get_heading(),
+            pen_down: self.is_pen_down(),
+            color_index: self.get_pen_color(),
+        }
+    }
+
+    // The canvas pixel at logical (0, 0)
+    fn origin_pixel(&self) -> (i32, i32) {
+        match self.origin {
+            Origin::TopLeft => (0, 0),
+            Origin::Center => (self.home_x, self.home_y),
+            Origin::BottomLeft => (0, self.height as i32),
+        }
+    }
+
+    fn y_sign(&self) -> i32 {
+        match self.y_axis {
+            YAxis::Down => 1,
+            YAxis::Up => -1,
+        }
+    }
+
+    fn pixel_x(&self, x: i32) -> i32 {
+        self.origin_pixel().0.saturating_add(x)
+    }
+
+    fn pixel_y(&self, y: i32) -> i32 {
+        self.origin_pixel()
+            .1
+            .saturating_add(y.saturating_mul(self.y_sign()))
+    }
+
+    // The drawing as it is written out. With SHOWTURTLE the turtle is added
+    // as a small triangle pointing along the heading, on a copy so saving
+    // more than once never leaves a cursor behind in the drawing itself.
+    fn output_image(&self) -> Result<Image, RSLogoError> {
+        let mut image = self.image.clone();
+        if self.show_turtle {
+            let heading = whole_degrees(self.heading);
+            let tip = unsvg::get_end_coordinates(self.x, self.y, heading, 8);
+            let left = unsvg::get_end_coordinates(self.x, self.y, heading - 135, 5);
+            let right = unsvg::get_end_coordinates(self.x, self.y, heading + 135, 5);
+            for (from, to) in [(tip, left), (left, right), (right, tip)] {
+                draw_segment(&mut image, from, to, CURSOR_COLOR)?;
+            }
+        }
+        Ok(image)
+    }
+
+    fn paint_background(&mut self) -> Result<(), RSLogoError> {
+        if let Some(color) = self.background {
+            // unsvg can only draw lines, so the background is laid down one row at a time
+            for row in 0..self.height as i32 {
+                self.draw_line(0, row, 90, self.width as i32, color)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Head straight for a point, drawing if the pen is down. Like ARC this
+    // goes wherever it is told regardless of the edge mode.
+    fn line_to(&mut self, target: (i32, i32)) -> Result<(), RSLogoError> {
+        if self.pen_down && target != (self.x, self.y) {
+            let dx = (target.0 - self.x) as f64;
+            let dy = (target.1 - self.y) as f64;
+            let direction = dx.atan2(-dy).to_degrees().round() as i32;
+            let length = dx.hypot(dy).round() as i32;
+            self.draw_thick_line(direction, length)?;
+        }
+        self.set_x(target.0);
+        self.set_y(target.1);
+        if let Some(points) = self.fill_points.as_mut() {
+            points.push(target);
+        }
+        Ok(())
     }
 
-    fn process_movement(&mut self, numpixels: i32, direction: i32) -> Result<(), RSLogoError> {
+    fn process_movement(&mut self, numpixels: f64, direction: f64) -> Result<(), RSLogoError> {
+        let direction = whole_degrees(direction);
+        // The canvas works in whole pixels, so fractional distances are rounded here
+        let numpixels = numpixels.round() as i32;
+        match self.edge_mode {
+            EdgeMode::Ignore => self.move_straight(direction, numpixels)?,
+            EdgeMode::Clamp => {
+                let (fraction, _, _) = self.edge_crossing(direction, numpixels);
+                self.move_straight(direction, (numpixels as f64 * fraction).round() as i32)?;
+            }
+            EdgeMode::Wrap => self.move_wrapping(direction, numpixels)?,
+        }
+        self.capture_frame();
+        Ok(())
+    }
+
+    fn move_straight(&mut self, direction: i32, numpixels: i32) -> Result<(), RSLogoError> {
         let new_position = if self.pen_down {
-            self.image
-                .draw_simple_line(self.x, self.y, direction, numpixels, self.color)
-                .map_err(|e| RSLogoError::DrawError(e.to_string()))?
+            self.draw_thick_line(direction, numpixels)?
         } else {
             unsvg::get_end_coordinates(self.x, self.y, direction, numpixels)
         };
 
         self.set_x(new_position.0);
         self.set_y(new_position.1);
+        if let Some(points) = self.fill_points.as_mut() {
+            points.push(new_position);
+        }
+        Ok(())
+    }
+
+    // Split the move at each border it crosses, continuing from the opposite side
+    fn move_wrapping(&mut self, direction: i32, numpixels: i32) -> Result<(), RSLogoError> {
+        if self.width == 0 || self.height == 0 {
+            return self.move_straight(direction, numpixels);
+        }
+        let (step_x, step_y) = unit_vector(direction as f64);
+        let mut remaining = numpixels;
+        while remaining > 0 {
+            let (fraction, crosses_x, crosses_y) = self.edge_crossing(direction, remaining);
+            let step = (remaining as f64 * fraction).round() as i32;
+            self.move_straight(direction, step)?;
+            remaining -= step;
+            if !crosses_x && !crosses_y {
+                break;
+            }
+            if crosses_x {
+                let width = self.width as i32;
+                self.set_x(if step_x > 0.0 {
+                    self.x - width
+                } else {
+                    self.x + width
+                });
+            }
+            if crosses_y {
+                let height = self.height as i32;
+                self.set_y(if step_y > 0.0 {
+                    self.y - height
+                } else {
+                    self.y + height
+                });
+            }
+        }
         Ok(())
     }
+
+    // How much of a move stays on the canvas, as a fraction of its length,
+    // and whether it runs off a vertical or horizontal edge first
+    fn edge_crossing(&self, direction: i32, numpixels: i32) -> (f64, bool, bool) {
+        let (step_x, step_y) = unit_vector(direction as f64);
+        let length = numpixels as f64;
+        let fraction_x = edge_fraction(self.x as f64, step_x * length, self.width as f64);
+        let fraction_y = edge_fraction(self.y as f64, step_y * length, self.height as f64);
+        let fraction = fraction_x.min(fraction_y);
+        (
+            fraction,
+            fraction_x < 1.0 && fraction_x <= fraction_y,
+            fraction_y < 1.0 && fraction_y <= fraction_x,
+        )
+    }
+
+    // Every line goes through here so an animation sees the same strokes
+    // as the image. Positions are logical canvas pixels; --scale is only
+    // applied to the finished output.
+    fn draw_line(
+        &mut self,
+        x: i32,
+        y: i32,
+        direction: i32,
+        length: i32,
+        color: Color,
+    ) -> Result<(i32, i32), RSLogoError> {
+        if let Some(animation) = self.animation.as_mut() {
+            animation.record_line(x, y, direction, length, color);
+        }
+        self.image
+            .draw_simple_line(x, y, direction, length, color)
+            .map_err(|e| RSLogoError::DrawError(e.to_string()))?;
+        self.lines_drawn += 1;
+        Ok(unsvg::get_end_coordinates(x, y, direction, length))
+    }
+
+    fn blank_image(&self) -> Image {
+        Image::new(self.width, self.height)
+    }
+
+    // SETSPEED: capture an animation frame every this many movements
+    pub fn set_steps_per_frame(&mut self, steps: usize) {
+        if let Some(animation) = self.animation.as_mut() {
+            animation.set_steps_per_frame(steps);
+        }
+    }
+
+    // Only an animation has time to spend; a still image ignores WAIT
+    pub fn wait(&mut self, centiseconds: u32) {
+        if let Some(animation) = self.animation.as_mut() {
+            animation.hold(centiseconds.saturating_mul(10));
+        }
+    }
+
+    fn capture_frame(&mut self) {
+        if let Some(animation) = self.animation.as_mut() {
+            animation.capture_frame();
+        }
+    }
+
+    // Scanline fill using the even-odd rule. unsvg has no polygon primitive,
+    // so each row inside the shape is drawn as a horizontal line.
+    fn fill_polygon(&mut self, points: &[(i32, i32)]) -> Result<(), RSLogoError> {
+        // Only rows and columns on the canvas get filled, so a shape that
+        // wanders far off the edge doesn't scan millions of empty pixels
+        let min_y = points.iter().map(|p| p.1).min().unwrap_or(0).max(0);
+        let max_y = points
+            .iter()
+            .map(|p| p.1)
+            .max()
+            .unwrap_or(0)
+            .min(self.height as i32 - 1);
+
+        for row in min_y..=max_y {
+            let scan_y = row as f64 + 0.5;
+            let mut crossings = Vec::new();
+            for (i, &(x1, y1)) in points.iter().enumerate() {
+                let (x2, y2) = points[(i + 1) % points.len()];
+                let (y1, y2) = (y1 as f64, y2 as f64);
+                if (y1 <= scan_y && scan_y < y2) || (y2 <= scan_y && scan_y < y1) {
+                    let t = (scan_y - y1) / (y2 - y1);
+                    crossings.push(x1 as f64 + t * (x2 - x1) as f64);
+                }
+            }
+            crossings.sort_by(|a, b| a.total_cmp(b));
+
+            for pair in crossings.chunks_exact(2) {
+                let start = (pair[0].round() as i32).max(0);
+                let end = (pair[1].round() as i32).min(self.width as i32);
+                if end > start {
+                    self.draw_line(start, row, 90, end - start, self.color)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // unsvg lines are always 1px wide, so wider pens are drawn as parallel
+    // lines offset either side of the turtle's path
+    fn draw_thick_line(
+        &mut self,
+        direction: i32,
+        numpixels: i32,
+    ) -> Result<(i32, i32), RSLogoError> {
+        let size = self.pen_size as i32;
+        let mut end = (self.x, self.y);
+        let mut reversed = Vec::new();
+        for offset in -(size - 1) / 2..=size / 2 {
+            let (start_x, start_y) = if offset < 0 {
+                unsvg::get_end_coordinates(self.x, self.y, direction - 90, -offset)
+            } else {
+                unsvg::get_end_coordinates(self.x, self.y, direction + 90, offset)
+            };
+            let line_end = if self.reverse_pen {
+                let line_end = unsvg::get_end_coordinates(start_x, start_y, direction, numpixels);
+                reversed.push(((start_x, start_y), line_end));
+                line_end
+            } else {
+                self.draw_line(start_x, start_y, direction, numpixels, self.color)?
+            };
+            if offset == 0 {
+                end = line_end;
+            }
+        }
+        if self.reverse_pen {
+            self.reverse_strokes.push(ReverseStroke {
+                pixels: rs_reverse::stroke_pixels(&reversed, self.width, self.height),
+                color: self.color,
+            });
+        }
+        if let Some(segments) = self.segments.as_mut() {
+            segments.push(Segment {
+                from: (self.x, self.y),
+                to: end,
+                color: self.color,
+            });
+        }
+        Ok(end)
+    }
+}
+
+fn temp_file_path(extension: &str) -> PathBuf {
+    std::env::temp_dir().join(format!(
+        "rustle-{}-{}.{}",
+        std::process::id(),
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or(0),
+        extension
+    ))
+}
+
+// unsvg only rasterizes straight to a PNG file, so other raster formats
+// decode that file back into pixels
+pub fn rasterize(source: &Image) -> Result<RgbaImage, RSLogoError> {
+    let temp_path = temp_file_path("png");
+    source
+        .save_png(&temp_path)
+        .map_err(|e| RSLogoError::ImageSaveError(e.to_string()))?;
+    let pixels = image::open(&temp_path);
+    let _ = fs::remove_file(&temp_path);
+    pixels
+        .map(|decoded| decoded.to_rgba8())
+        .map_err(|e| RSLogoError::ImageSaveError(e.to_string()))
+}
+
+// A 1px line between two arbitrary points
+fn draw_segment(
+    image: &mut Image,
+    from: (i32, i32),
+    to: (i32, i32),
+    color: Color,
+) -> Result<(), RSLogoError> {
+    let dx = (to.0 - from.0) as f64;
+    let dy = (to.1 - from.1) as f64;
+    let direction = dx.atan2(-dy).to_degrees().round() as i32;
+    let length = dx.hypot(dy).round() as i32;
+    image
+        .draw_simple_line(from.0, from.1, direction, length, color)
+        .map_err(|e| RSLogoError::DrawError(e.to_string()))?;
+    Ok(())
+}
+
+// The output size for --scale, or an error if it doesn't fit in a u32
+pub fn scaled_size(width: u32, height: u32, scale: u32) -> Result<(u32, u32), RSLogoError> {
+    match (width.checked_mul(scale), height.checked_mul(scale)) {
+        (Some(width), Some(height)) => Ok((width, height)),
+        _ => Err(RSLogoError::InvalidArgument {
+            command: "--scale".to_string(),
+            argument: scale.to_string(),
+            expected: format!(
+                "a scale that keeps a {}x{} canvas within {} pixels a side",
+                width,
+                height,
+                u32::MAX
+            ),
+        }),
+    }
+}
+
+// Each canvas pixel becomes a scale x scale block, so lines stay sharp.
+// The size was checked by set_scale.
+pub fn enlarge(pixels: RgbaImage, scale: u32) -> RgbaImage {
+    if scale == 1 {
+        return pixels;
+    }
+    let (width, height) = (pixels.width() * scale, pixels.height() * scale);
+    imageops::resize(&pixels, width, height, FilterType::Nearest)
+}
+
+// Fraction of a move along one axis before it reaches 0 or `limit`
+fn edge_fraction(start: f64, delta: f64, limit: f64) -> f64 {
+    let fraction = if delta > 0.0 {
+        (limit - start) / delta
+    } else if delta < 0.0 {
+        -start / delta
+    } else {
+        1.0
+    };
+    fraction.clamp(0.0, 1.0)
+}
+
+// rem_euclid can round a tiny negative angle up to 360 itself, which is
+// the same direction as 0
+fn normalize_heading(degrees: f64) -> f64 {
+    let heading = degrees.rem_euclid(360.0);
+    if heading < 360.0 {
+        heading
+    } else {
+        0.0
+    }
+}
+
+// The direction unsvg draws a heading at. BACK, LEFT and RIGHT add to the
+// heading, so this also brings their directions back to [0, 360).
+fn whole_degrees(heading: f64) -> i32 {
+    (normalize_heading(heading).round() as i32).rem_euclid(360)
+}
+
+// Direction of travel for a heading, where 0 points up the canvas
+fn unit_vector(heading: f64) -> (f64, f64) {
+    let radians = heading.to_radians();
+    (radians.sin(), -radians.cos())
+}
+
+pub fn parse_origin(spec: &str) -> Result<Origin, RSLogoError> {
+    match spec {
+        "topleft" => Ok(Origin::TopLeft),
+        "center" => Ok(Origin::Center),
+        "bottomleft" => Ok(Origin::BottomLeft),
+        _ => Err(RSLogoError::InvalidArgument {
+            command: "--origin".to_string(),
+            argument: spec.to_string(),
+            expected: "topleft, center, or bottomleft".to_string(),
+        }),
+    }
+}
+
+pub fn parse_y_axis(spec: &str) -> Result<YAxis, RSLogoError> {
+    match spec {
+        "down" => Ok(YAxis::Down),
+        "up" => Ok(YAxis::Up),
+        _ => Err(RSLogoError::InvalidArgument {
+            command: "--y-axis".to_string(),
+            argument: spec.to_string(),
+            expected: "down or up".to_string(),
+        }),
+    }
+}
+
+pub fn parse_edge_mode(spec: &str) -> Result<EdgeMode, RSLogoError> {
+    match spec {
+        "clamp" => Ok(EdgeMode::Clamp),
+        "wrap" => Ok(EdgeMode::Wrap),
+        "ignore" => Ok(EdgeMode::Ignore),
+        _ => Err(RSLogoError::InvalidArgument {
+            command: "--edge-mode".to_string(),
+            argument: spec.to_string(),
+            expected: "clamp, wrap, or ignore".to_string(),
+        }),
+    }
+}
+
+// How every palette index error describes the valid range
+fn palette_index_expected(palette: &[Color]) -> String {
+    format!("a palette index between 0 and {}", palette.len() - 1)
+}
+
+pub fn default_palette() -> Vec<Color> {
+    COLORS.to_vec()
+}
+
+// Read a --palette file: one #RRGGBB or #RGB color per line, at most as many
+// as the default palette has. Blank lines are skipped.
+pub fn parse_palette(text: &str) -> Result<Vec<Color>, RSLogoError> {
+    let mut palette = Vec::new();
+    for line in text.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        let color = parse_hex_color(line).ok_or_else(|| RSLogoError::InvalidArgument {
+            command: "--palette".to_string(),
+            argument: line.to_string(),
+            expected: "a #RRGGBB or #RGB hex color on each line".to_string(),
+        })?;
+        palette.push(color);
+    }
+    if palette.is_empty() || palette.len() > COLORS.len() {
+        return Err(RSLogoError::InvalidArgument {
+            command: "--palette".to_string(),
+            argument: format!("{} colors", palette.len()),
+            expected: format!("between 1 and {} colors", COLORS.len()),
+        });
+    }
+    Ok(palette)
+}
+
+// #RRGGBB, or the #RGB shorthand where each digit is doubled (#F80 is
+// #FF8800). None for anything else.
+fn parse_hex_color(spec: &str) -> Option<Color> {
+    let hex = spec.strip_prefix('#')?;
+    if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    let channel = |digits: &str| u8::from_str_radix(digits, 16).ok();
+    match hex.len() {
+        6 => Some(Color {
+            red: channel(&hex[0..2])?,
+            green: channel(&hex[2..4])?,
+            blue: channel(&hex[4..6])?,
+        }),
+        3 => Some(Color {
+            red: channel(&hex[0..1])? * 17,
+            green: channel(&hex[1..2])? * 17,
+            blue: channel(&hex[2..3])? * 17,
+        }),
+        _ => None,
+    }
+}
+
+// Parse a color given as an index into palette, a color name as SETPENCOLOR
+// takes it, or #RRGGBB or #RGB
+pub fn parse_color(spec: &str, palette: &[Color]) -> Result<Color, RSLogoError> {
+    let invalid = || RSLogoError::InvalidArgument {
+        command: "color".to_string(),
+        argument: spec.to_string(),
+        expected: format!(
+            "{}, a color name or a #RRGGBB or #RGB hex color",
+            palette_index_expected(palette)
+        ),
+    };
+
+    if spec.starts_with('#') {
+        return parse_hex_color(spec).ok_or_else(invalid);
+    }
+
+    let index = match COLOR_NAMES
+        .iter()
+        .position(|known| known.eq_ignore_ascii_case(spec))
+    {
+        Some(index) => Some(index),
+        None => spec.parse::<usize>().ok(),
+    };
+    match index {
+        Some(index) if index < palette.len() => Ok(palette[index]),
+        _ => Err(invalid()),
+    }
 }
@@ -3,11 +3,13 @@ use std::collections::HashMap;
 
 #[derive(Clone)]
 pub struct VariableScope {
-    variables: HashMap<String, Value>,
+    // A LOCAL that has been declared but not yet given a value maps to None
+    variables: HashMap<String, Option<Value>>,
 }
 
 pub struct VariableManager {
-    current_scope: VariableScope,
+    // Innermost scope last; the first entry is the global scope
+    scopes: Vec<VariableScope>,
 }
 
 impl VariableScope {
@@ -21,36 +23,114 @@ impl VariableScope {
 impl VariableManager {
     pub fn new() -> Self {
         Self {
-            current_scope: VariableScope::new(),
+            scopes: vec![VariableScope::new()],
         }
     }
 
+    pub fn push_scope(&mut self) {
+        self.scopes.push(VariableScope::new());
+    }
+
+    pub fn pop_scope(&mut self) {
+        // The global scope is never popped
+        if self.scopes.len() > 1 {
+            self.scopes.pop();
+        }
+    }
+
+    // Declare a binding in the innermost scope that shadows any outer variable
+    pub fn declare_local(&mut self, name: &str) {
+        trace!("Declaring local variable: {}", name);
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.variables.entry(name.to_string()).or_insert(None);
+        }
+    }
+
+    // Make sure a global binding exists, so MAKE inside a procedure call
+    // updates it rather than creating one of the call's own
+    pub fn declare_global(&mut self, name: &str) {
+        trace!("Declaring global variable: {}", name);
+        self.scopes[0]
+            .variables
+            .entry(name.to_string())
+            .or_insert(None);
+    }
+
     pub fn set(&mut self, name: &str, value: Value) {
-        let stored_value = match value {
-            Value::String(ref s) if s.to_uppercase() == "TRUE" => Value::Boolean(true),
-            Value::String(ref s) if s.to_uppercase() == "FALSE" => Value::Boolean(false),
-            Value::String(ref s) => {
-                if let Ok(n) = s.parse::<i32>() {
-                    Value::Number(n)
-                } else {
-                    value.clone()
-                }
-            }
-            _ => value.clone(),
+        let stored_value = normalize(value);
+        trace!("Setting variable: {} = {:?}", name, stored_value);
+
+        // Update the innermost existing binding, otherwise create a global one
+        let scope = match self
+            .scopes
+            .iter()
+            .rposition(|scope| scope.variables.contains_key(name))
+        {
+            Some(index) => &mut self.scopes[index],
+            None => &mut self.scopes[0],
         };
-        println!("Setting variable: {} = {:?}", name, stored_value);
-        self.current_scope
+        scope.variables.insert(name.to_string(), Some(stored_value));
+    }
+
+    // MAKE writes to the innermost scope or the global one, never to a
+    // caller's: a name bound in neither gets a new binding in the innermost
+    // scope, so recursive calls don't clobber each other's temporaries.
+    // Outside any procedure the innermost scope is the global one.
+    pub fn make(&mut self, name: &str, value: Value) {
+        let stored_value = normalize(value);
+        trace!("Making variable: {} = {:?}", name, stored_value);
+
+        let innermost = self.scopes.len() - 1;
+        let index = if !self.scopes[innermost].variables.contains_key(name)
+            && self.scopes[0].variables.contains_key(name)
+        {
+            0
+        } else {
+            innermost
+        };
+        self.scopes[index]
             .variables
-            .insert(name.to_string(), stored_value);
+            .insert(name.to_string(), Some(stored_value));
     }
 
     pub fn get(&self, name: &str) -> Option<&Value> {
-        let value = self.current_scope.variables.get(name);
-        println!("Getting variable: {} = {:?}", name, value);
+        // Walk from the innermost scope outward; an unset LOCAL still shadows
+        let value = self
+            .scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.variables.get(name))
+            .and_then(|value| value.as_ref());
+        trace!("Getting variable: {} = {:?}", name, value);
         value
     }
 
     pub fn get_all_names(&self) -> Vec<String> {
-        self.current_scope.variables.keys().cloned().collect()
+        let mut names: Vec<String> = Vec::new();
+        for scope in &self.scopes {
+            for (name, value) in &scope.variables {
+                if value.is_some() && !names.contains(name) {
+                    names.push(name.clone());
+                }
+            }
+        }
+        names
+    }
+}
+
+fn normalize(value: Value) -> Value {
+    match value {
+        Value::String(ref s) if s.to_uppercase() == "TRUE" => Value::Boolean(true),
+        Value::String(ref s) if s.to_uppercase() == "FALSE" => Value::Boolean(false),
+        Value::String(ref s) => {
+            if let Ok(n) = s.parse::<i32>() {
+                Value::Number(n)
+            } else if let Ok(x) = s.parse::<f64>() {
+                Value::Float(x)
+            } else {
+                value
+            }
+        }
+        _ => value,
     }
 }
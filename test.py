@@ -2,6 +2,9 @@
 import sys
 import subprocess
 import os 
+import json
+import re
+import zlib
 
 # Find the test files by the numeric prefix
 def find_files_by_prefix(directory, prefix):
@@ -11,6 +14,61 @@ def find_files_by_prefix(directory, prefix):
             matching_files.append(filename)
     return matching_files[0] # Always return first file as prefix is unique
 
+# The delay field of every Graphic Control Extension, in frame order
+def gif_frame_delays(data):
+    delays = []
+    start = 0
+    while True:
+        start = data.find(b"\x21\xf9\x04", start)
+        if start == -1:
+            return delays
+        delays.append(int.from_bytes(data[start + 4:start + 6], "little"))
+        start += 8
+
+# How many pixels of an 8-bit RGBA PNG (as written by the interpreter) are
+# not fully transparent
+def png_drawn_pixels(data):
+    width = int.from_bytes(data[16:20], "big")
+    height = int.from_bytes(data[20:24], "big")
+    compressed = b""
+    pos = 8
+    while pos < len(data):
+        length = int.from_bytes(data[pos:pos + 4], "big")
+        if data[pos + 4:pos + 8] == b"IDAT":
+            compressed += data[pos + 8:pos + 8 + length]
+        pos += 12 + length
+    raw = zlib.decompress(compressed)
+    stride = width * 4
+    previous = bytearray(stride)
+    drawn = 0
+    for row in range(height):
+        start = row * (stride + 1)
+        kind = raw[start]
+        line = bytearray(raw[start + 1:start + 1 + stride])
+        for i in range(stride):
+            left = line[i - 4] if i >= 4 else 0
+            up = previous[i]
+            up_left = previous[i - 4] if i >= 4 else 0
+            if kind == 1:
+                line[i] = (line[i] + left) & 0xff
+            elif kind == 2:
+                line[i] = (line[i] + up) & 0xff
+            elif kind == 3:
+                line[i] = (line[i] + (left + up) // 2) & 0xff
+            elif kind == 4:
+                p = left + up - up_left
+                pa, pb, pc = abs(p - left), abs(p - up), abs(p - up_left)
+                if pa <= pb and pa <= pc:
+                    predictor = left
+                elif pb <= pc:
+                    predictor = up
+                else:
+                    predictor = up_left
+                line[i] = (line[i] + predictor) & 0xff
+        drawn += sum(1 for i in range(3, stride, 4) if line[i] != 0)
+        previous = line
+    return drawn
+
 def prase_command():
     prefix = sys.argv[1]
     dir = "logo_examples"
@@ -18,10 +76,55 @@ def prase_command():
     in_file = "logo_examples/"+ find_files_by_prefix(dir, prefix)
     out_svg_file = "output.svg"
     out_png_file = "output.png"
-    command1 = ["cargo", "run", "--", in_file, out_png_file ,"200", "200"]
-    command2 = ["cargo", "run", "--", in_file, out_svg_file ,"200", "200"]
-    result = subprocess.run(command1, capture_output=True, text=True)
-    result = subprocess.run(command2, capture_output=True, text=True)
+    out_jpg_file = "output.jpg"
+    out_gif_file = "output.gif"
+    # With --stdin, the program is piped in rather than read from a path
+    use_stdin = "--stdin" in sys.argv[2:]
+    # With --count-pixels, report how much of the PNG was drawn on
+    count_pixels = "--count-pixels" in sys.argv[2:]
+    # Anything else after the prefix is passed through, e.g. --error-format json
+    extra_args = [arg for arg in sys.argv[2:] if arg not in ("--stdin", "--count-pixels")]
+    in_arg = "-" if use_stdin else in_file
+    stdin_text = open(in_file).read() if use_stdin else None
+    command1 = ["cargo", "run", "--", in_arg, out_png_file ,"200", "200"] + extra_args
+    command2 = ["cargo", "run", "--", in_arg, out_svg_file ,"200", "200"] + extra_args
+    command3 = ["cargo", "run", "--", in_arg, out_jpg_file ,"200", "200"] + extra_args
+    result = subprocess.run(command1, input=stdin_text, capture_output=True, text=True)
+    if count_pixels and result.returncode == 0:
+        with open(out_png_file, "rb") as png:
+            print("Drawn PNG pixels:", png_drawn_pixels(png.read()))
+    result = subprocess.run(command3, input=stdin_text, capture_output=True, text=True)
+    # Every JPEG starts with the SOI marker followed by another marker
+    if result.returncode == 0:
+        with open(out_jpg_file, "rb") as jpg:
+            print("JPEG header valid:", jpg.read(3) == b"\xff\xd8\xff")
+    # With --animate, also write a GIF and show how long each frame is held
+    if "--animate" in extra_args:
+        command4 = ["cargo", "run", "--", in_arg, out_gif_file ,"200", "200"] + extra_args
+        result = subprocess.run(command4, input=stdin_text, capture_output=True, text=True)
+        if result.returncode == 0:
+            with open(out_gif_file, "rb") as gif:
+                print("GIF frame delays (1/100 s):", gif_frame_delays(gif.read()))
+    result = subprocess.run(command2, input=stdin_text, capture_output=True, text=True)
+    # With --export-path, show how many segments were written
+    if "--export-path" in extra_args and result.returncode == 0:
+        export_file = extra_args[extra_args.index("--export-path") + 1]
+        with open(export_file) as exported:
+            print("Exported segments:", len(json.load(exported)))
+    # LABEL text shows up as <text> elements in the SVG
+    if result.returncode == 0:
+        with open(out_svg_file) as svg:
+            svg_text = svg.read()
+            print("SVG text elements:", svg_text.count("<text"))
+            # The canvas is filled first; a line stroked in the same color
+            # can't be seen
+            fill = re.search(r'fill="(#[0-9a-fA-F]{6})" stroke="none"', svg_text)
+            if fill:
+                hidden = svg_text.count('stroke="%s"' % fill.group(1))
+                print("Lines in the background color:", hidden)
+            # GROUP adds <g> elements, which only some programs use
+            if "<g " in svg_text:
+                print("SVG groups:", svg_text.count("<g "))
     # Print the error
     print("Output:", result.stdout)
     print("Error (if any):", result.stderr)